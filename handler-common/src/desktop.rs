@@ -0,0 +1,321 @@
+// Linux 下通过 .desktop 文件和 mimeapps.list 注册 mpv:// 协议
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub const DESKTOP_FILE: &str = "mpv-handler.desktop";
+pub const MIME_TYPE: &str = "x-scheme-handler/mpv";
+
+const DEFAULT_SECTION: &str = "[Default Applications]";
+
+// .desktop 文件和 mimeapps.list 的位置
+pub struct Paths {
+    pub applications: PathBuf,
+    pub mimeapps: PathBuf,
+}
+
+impl Paths {
+    // 当前用户：~/.local/share/applications 和 ~/.config/mimeapps.list
+    pub fn user() -> io::Result<Paths> {
+        let home = std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME not set"))?;
+        let data_home = xdg_dir("XDG_DATA_HOME").unwrap_or_else(|| home.join(".local/share"));
+        let config_home = xdg_dir("XDG_CONFIG_HOME").unwrap_or_else(|| home.join(".config"));
+
+        Ok(Paths {
+            applications: data_home.join("applications"),
+            mimeapps: config_home.join("mimeapps.list"),
+        })
+    }
+
+    // 所有用户：/usr/share/applications 和 /etc/xdg/mimeapps.list
+    pub fn system() -> Paths {
+        Paths {
+            applications: PathBuf::from("/usr/share/applications"),
+            mimeapps: PathBuf::from("/etc/xdg/mimeapps.list"),
+        }
+    }
+
+    pub fn desktop_file(&self) -> PathBuf {
+        self.applications.join(DESKTOP_FILE)
+    }
+}
+
+fn xdg_dir(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+}
+
+// 生成 .desktop 文件内容
+pub fn desktop_entry(exe_path: &str) -> String {
+    format!(
+        "[Desktop Entry]
+Type=Application
+Name=mpv-handler
+Comment=Play Emby videos with mpv
+Exec={} %u
+Terminal=false
+NoDisplay=true
+MimeType={};
+",
+        quote_exec(exe_path),
+        MIME_TYPE
+    )
+}
+
+// 按 Desktop Entry 规范转义 Exec 中的路径
+fn quote_exec(path: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in path.chars() {
+        match c {
+            // 字符串值本身还会再转义一次反斜杠
+            '"' | '`' | '$' => {
+                quoted.push_str("\\\\");
+                quoted.push(c);
+            }
+            '\\' => quoted.push_str("\\\\\\\\"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 读取 mimeapps.list 中 mpv:// 的默认程序
+pub fn default_handler(mimeapps: &str) -> Option<String> {
+    let mut in_default = false;
+
+    for line in mimeapps.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_default = line == DEFAULT_SECTION;
+        } else if in_default {
+            if let Some(value) = line
+                .strip_prefix(MIME_TYPE)
+                .and_then(|rest| rest.trim_start().strip_prefix('='))
+            {
+                return value
+                    .split(';')
+                    .map(str::trim)
+                    .find(|entry| !entry.is_empty())
+                    .map(str::to_string);
+            }
+        }
+    }
+
+    None
+}
+
+// 更新 mimeapps.list 内容，desktop 为 None 时移除 mpv:// 的默认程序
+pub fn set_default_handler(mimeapps: &str, desktop: Option<&str>) -> String {
+    let entry = desktop.map(|desktop| format!("{}={};", MIME_TYPE, desktop));
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut in_default = false;
+    let mut has_section = false;
+    let mut written = false;
+
+    for line in mimeapps.lines() {
+        let trimmed = line.trim();
+
+        if trimmed.starts_with('[') {
+            // 离开默认程序段时补上条目
+            if in_default && !written {
+                insert_before_blank(&mut lines, entry.as_deref());
+                written = true;
+            }
+            in_default = trimmed == DEFAULT_SECTION;
+            has_section |= in_default;
+        } else if in_default
+            && trimmed
+                .strip_prefix(MIME_TYPE)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        {
+            if !written {
+                lines.extend(entry.clone());
+                written = true;
+            }
+            continue;
+        }
+
+        lines.push(line.to_string());
+    }
+
+    if !written {
+        if let Some(entry) = entry {
+            if !has_section {
+                if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                    lines.push(String::new());
+                }
+                lines.push(DEFAULT_SECTION.to_string());
+            }
+            lines.push(entry);
+        }
+    }
+
+    let mut content = lines.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    content
+}
+
+fn insert_before_blank(lines: &mut Vec<String>, entry: Option<&str>) {
+    let Some(entry) = entry else {
+        return;
+    };
+
+    let index = lines
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(lines.len(), |i| i + 1);
+    lines.insert(index, entry.to_string());
+}
+
+// 当前注册的其他程序，没有冲突时返回 None
+pub fn conflict(paths: &Paths) -> io::Result<Option<String>> {
+    let current = match query_default() {
+        Some(current) => Some(current),
+        None => default_handler(&read_or_empty(&paths.mimeapps)?),
+    };
+
+    Ok(current.filter(|current| current != DESKTOP_FILE))
+}
+
+// 通过 xdg-mime 查询，能反映系统级的关联
+fn query_default() -> Option<String> {
+    let output = Command::new("xdg-mime")
+        .args(["query", "default", MIME_TYPE])
+        .output()
+        .ok()?;
+
+    let current = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (output.status.success() && !current.is_empty()).then_some(current)
+}
+
+// 写入 .desktop 文件并设为默认程序，返回被替换的程序
+pub fn install(paths: &Paths, exe_path: &str) -> io::Result<Option<String>> {
+    let replaced = conflict(paths)?;
+
+    fs::create_dir_all(&paths.applications)?;
+    fs::write(paths.desktop_file(), desktop_entry(exe_path))?;
+
+    write_mimeapps(&paths.mimeapps, Some(DESKTOP_FILE))?;
+    update_database(&paths.applications);
+
+    Ok(replaced)
+}
+
+// 删除 .desktop 文件和默认程序关联，返回是否做了修改
+pub fn uninstall(paths: &Paths) -> io::Result<bool> {
+    let mut changed = false;
+
+    let desktop_file = paths.desktop_file();
+    if desktop_file.exists() {
+        fs::remove_file(&desktop_file)?;
+        changed = true;
+    }
+
+    let mimeapps = read_or_empty(&paths.mimeapps)?;
+    if default_handler(&mimeapps).as_deref() == Some(DESKTOP_FILE) {
+        write_mimeapps(&paths.mimeapps, None)?;
+        changed = true;
+    }
+
+    if changed {
+        update_database(&paths.applications);
+    }
+
+    Ok(changed)
+}
+
+fn write_mimeapps(path: &Path, desktop: Option<&str>) -> io::Result<()> {
+    let content = set_default_handler(&read_or_empty(path)?, desktop);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)
+}
+
+fn read_or_empty(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// 刷新桌面数据库，没有安装 update-desktop-database 时忽略
+fn update_database(applications: &Path) {
+    let _ = Command::new("update-desktop-database")
+        .arg(applications)
+        .output();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_handler, set_default_handler, DESKTOP_FILE};
+
+    #[test]
+    fn adds_entry_to_existing_section() {
+        let mimeapps = "[Default Applications]\ntext/html=firefox.desktop\n\n[Added Associations]\nimage/png=gimp.desktop;\n";
+        let updated = set_default_handler(mimeapps, Some(DESKTOP_FILE));
+
+        assert_eq!(
+            updated,
+            "[Default Applications]\ntext/html=firefox.desktop\nx-scheme-handler/mpv=mpv-handler.desktop;\n\n[Added Associations]\nimage/png=gimp.desktop;\n"
+        );
+        assert_eq!(default_handler(&updated).as_deref(), Some(DESKTOP_FILE));
+    }
+
+    #[test]
+    fn creates_missing_section() {
+        let mimeapps = "[Added Associations]\nimage/png=gimp.desktop;\n";
+        let updated = set_default_handler(mimeapps, Some(DESKTOP_FILE));
+
+        assert_eq!(
+            updated,
+            "[Added Associations]\nimage/png=gimp.desktop;\n\n[Default Applications]\nx-scheme-handler/mpv=mpv-handler.desktop;\n"
+        );
+        assert_eq!(
+            set_default_handler("", Some(DESKTOP_FILE)),
+            "[Default Applications]\nx-scheme-handler/mpv=mpv-handler.desktop;\n"
+        );
+    }
+
+    #[test]
+    fn replaces_duplicate_keys() {
+        let mimeapps = "[Default Applications]\nx-scheme-handler/mpv=old.desktop;\ntext/html=firefox.desktop\nx-scheme-handler/mpv = other.desktop\n";
+        assert_eq!(default_handler(mimeapps).as_deref(), Some("old.desktop"));
+
+        let updated = set_default_handler(mimeapps, Some(DESKTOP_FILE));
+        assert_eq!(
+            updated,
+            "[Default Applications]\nx-scheme-handler/mpv=mpv-handler.desktop;\ntext/html=firefox.desktop\n"
+        );
+
+        let removed = set_default_handler(mimeapps, None);
+        assert_eq!(
+            removed,
+            "[Default Applications]\ntext/html=firefox.desktop\n"
+        );
+        assert_eq!(default_handler(&removed), None);
+    }
+
+    #[test]
+    fn keeps_unrelated_lines() {
+        let mimeapps = "# user settings\n[Added Associations]\nx-scheme-handler/mpv=added.desktop;\n\n[Default Applications]\n  text/html=firefox.desktop  \nx-scheme-handler/mpvx=other.desktop\n";
+        let updated = set_default_handler(mimeapps, Some(DESKTOP_FILE));
+
+        assert_eq!(
+            updated,
+            "# user settings\n[Added Associations]\nx-scheme-handler/mpv=added.desktop;\n\n[Default Applications]\n  text/html=firefox.desktop  \nx-scheme-handler/mpvx=other.desktop\nx-scheme-handler/mpv=mpv-handler.desktop;\n"
+        );
+        // 其他段中的同名条目不是默认程序
+        assert_eq!(default_handler(mimeapps), None);
+    }
+}
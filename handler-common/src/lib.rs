@@ -0,0 +1,3 @@
+// mpv-handler 和 handler-config 共用的 mpv 查找和 Linux 协议注册
+pub mod desktop;
+pub mod mpv;
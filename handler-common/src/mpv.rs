@@ -0,0 +1,234 @@
+// 查找本机安装的 mpv，并解析带参数的 mpv 启动命令
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+
+pub const FLATPAK_ID: &str = "io.mpv.Mpv";
+
+#[cfg(windows)]
+const EXE_NAME: &str = "mpv.exe";
+#[cfg(not(windows))]
+const EXE_NAME: &str = "mpv";
+
+// 拆分启动命令，如 `flatpak run io.mpv.Mpv`
+// 整体是一个存在的文件时不拆分，兼容带空格的路径
+pub fn split_command(command: &str) -> Vec<String> {
+    let command = command.trim();
+    if command.is_empty() {
+        return Vec::new();
+    }
+    if Path::new(command).is_file() {
+        return vec![command.to_string()];
+    }
+
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut in_part = false;
+
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_part = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_part {
+                    parts.push(std::mem::take(&mut current));
+                    in_part = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_part = true;
+            }
+        }
+    }
+    if in_part {
+        parts.push(current);
+    }
+
+    parts
+}
+
+// 在沙箱中运行的 mpv，无法访问主机的 /tmp
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sandbox {
+    Flatpak(String),
+    Snap(String),
+}
+
+impl Sandbox {
+    // 沙箱和主机共享的运行时目录，相对于 $XDG_RUNTIME_DIR
+    pub fn runtime_dir(&self) -> String {
+        match self {
+            Sandbox::Flatpak(app_id) => format!("app/{}", app_id),
+            Sandbox::Snap(name) => format!("snap.{}", name),
+        }
+    }
+}
+
+// 根据启动命令判断 mpv 是否运行在沙箱中
+pub fn sandbox(command: &str) -> Option<Sandbox> {
+    let parts = split_command(command);
+    let program = Path::new(parts.first()?);
+    let name = program.file_name()?.to_str()?;
+
+    if name == "flatpak" {
+        // flatpak run [OPTIONS] APP_ID [ARGS]
+        return parts
+            .iter()
+            .skip_while(|part| part.as_str() != "run")
+            .skip(1)
+            .find(|part| !part.starts_with('-'))
+            .map(|app_id| Sandbox::Flatpak(app_id.clone()));
+    }
+
+    if program.starts_with("/snap/bin") {
+        let name = name.split('.').next().unwrap_or(name);
+        return Some(Sandbox::Snap(name.to_string()));
+    }
+
+    None
+}
+
+// 根据启动命令构造 Command，命令为空时返回 None
+pub fn command(command: &str) -> Option<Command> {
+    let mut parts = split_command(command).into_iter();
+    let mut command = Command::new(parts.next()?);
+    command.args(parts);
+    Some(command)
+}
+
+// 获取 mpv --version 的第一行
+pub fn version(mpv: &str) -> Option<String> {
+    let mut command = command(mpv)?;
+    command.arg("--version");
+    #[cfg(windows)]
+    command.creation_flags(134_217_728);
+
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(str::to_string)
+}
+
+// 默认使用的 mpv，优先使用可执行文件
+pub fn find() -> Option<String> {
+    executables().into_iter().next().or_else(flatpak)
+}
+
+// 所有找到的 mpv 启动命令
+pub fn candidates() -> Vec<String> {
+    let mut found = executables();
+    found.extend(flatpak());
+    found
+}
+
+// PATH、常见安装目录、Snap 和 AppImage 中的 mpv
+fn executables() -> Vec<String> {
+    let mut paths: Vec<PathBuf> = std::env::var_os("PATH")
+        .map(|path| {
+            std::env::split_paths(&path)
+                .map(|dir| dir.join(EXE_NAME))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    paths.extend(install_dirs().into_iter().map(|dir| dir.join(EXE_NAME)));
+    paths.extend(app_images());
+
+    let mut seen = HashSet::new();
+    paths
+        .into_iter()
+        .filter(|path| path.is_file())
+        // 同一个文件可能通过软链接出现多次
+        .filter(|path| seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())))
+        .map(|path| path.display().to_string())
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn install_dirs() -> Vec<PathBuf> {
+    [
+        "/usr/bin",
+        "/usr/local/bin",
+        "/opt/homebrew/bin",
+        "/snap/bin",
+    ]
+    .into_iter()
+    .map(PathBuf::from)
+    .collect()
+}
+
+#[cfg(windows)]
+fn install_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    for (var, dir) in [
+        ("ProgramFiles", "mpv"),
+        ("LOCALAPPDATA", "Programs\\mpv"),
+        ("USERPROFILE", "scoop\\apps\\mpv\\current"),
+        ("ProgramData", "chocolatey\\bin"),
+    ] {
+        if let Some(base) = std::env::var_os(var) {
+            dirs.push(PathBuf::from(base).join(dir));
+        }
+    }
+
+    dirs
+}
+
+// ~/Applications、~/.local/bin、~/Downloads 和 /opt 中名为 mpv*.AppImage 的文件
+#[cfg(not(windows))]
+fn app_images() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("/opt")];
+    if let Some(home) = std::env::var_os("HOME").map(PathBuf::from) {
+        dirs.extend(["Applications", ".local/bin", "Downloads"].map(|dir| home.join(dir)));
+    }
+
+    let mut images: Vec<PathBuf> = dirs
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().to_lowercase())
+                .is_some_and(|name| name.starts_with("mpv") && name.ends_with(".appimage"))
+        })
+        .collect();
+    images.sort();
+    images
+}
+
+#[cfg(windows)]
+fn app_images() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+// 通过 Flatpak 安装的 mpv
+#[cfg(not(windows))]
+fn flatpak() -> Option<String> {
+    let installed = Command::new("flatpak")
+        .args(["info", FLATPAK_ID])
+        .output()
+        .is_ok_and(|output| output.status.success());
+
+    installed.then(|| format!("flatpak run {}", FLATPAK_ID))
+}
+
+#[cfg(windows)]
+fn flatpak() -> Option<String> {
+    None
+}
@@ -0,0 +1,354 @@
+// 读取和保存 mpv-handler.toml，保留注释和未知字段
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use toml_edit::{value, Array, ArrayOfTables, DocumentMut, Item, Table, Value};
+
+pub const SECRET_STORES: [&str; 3] = ["auto", "keyring", "file"];
+
+#[derive(Debug, Default, Clone)]
+pub struct ServerEntry {
+    pub name: String,
+    pub url: String,
+    pub api_key: String,
+    pub username: String,
+    pub password: String,
+    pub password_command: String,
+    pub quick_connect: bool,
+    // 在原文件 [[servers]] 中的位置，用于保留该段的其他字段
+    pub index: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Settings {
+    pub mpv: String,
+    pub proxy: String,
+    pub useragent: String,
+    pub mpv_args: Vec<String>,
+    pub device_name: String,
+    pub device_id: String,
+    pub secret_store: String,
+    pub servers: Vec<ServerEntry>,
+}
+
+pub struct ConfigFile {
+    pub path: PathBuf,
+    document: DocumentMut,
+}
+
+// mpv-handler 读取配置的位置
+pub fn config_path(handler_path: &str) -> Option<PathBuf> {
+    #[cfg(windows)]
+    return Path::new(handler_path)
+        .parent()
+        .map(|dir| dir.join("mpv-handler.toml"));
+    #[cfg(not(windows))]
+    {
+        let _ = handler_path;
+        std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".config/mpv-handler/mpv-handler.toml"))
+    }
+}
+
+impl ConfigFile {
+    // 文件不存在时返回空配置
+    pub fn load(path: &Path) -> io::Result<ConfigFile> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let document = data
+            .parse::<DocumentMut>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(ConfigFile {
+            path: path.to_path_buf(),
+            document,
+        })
+    }
+
+    pub fn settings(&self) -> Settings {
+        let root = self.document.as_table();
+
+        let servers = root
+            .get("servers")
+            .and_then(Item::as_array_of_tables)
+            .map(|servers| {
+                servers
+                    .iter()
+                    .enumerate()
+                    .map(|(index, server)| ServerEntry {
+                        name: get_str(server, "name"),
+                        url: get_str(server, "url"),
+                        api_key: get_str(server, "api_key"),
+                        username: get_str(server, "username"),
+                        password: get_str(server, "password"),
+                        password_command: get_str(server, "password_command"),
+                        quick_connect: server
+                            .get("quick_connect")
+                            .and_then(Item::as_bool)
+                            .unwrap_or(false),
+                        index: Some(index),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Settings {
+            mpv: get_str(root, "mpv"),
+            proxy: get_str(root, "proxy"),
+            useragent: get_str(root, "useragent"),
+            mpv_args: root
+                .get("mpv_args")
+                .and_then(Item::as_array)
+                .map(|args| {
+                    args.iter()
+                        .filter_map(|arg| arg.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            device_name: get_str(root, "device_name"),
+            device_id: get_str(root, "device_id"),
+            secret_store: get_str(root, "secret_store"),
+            servers,
+        }
+    }
+
+    // 写回修改过的字段，空字符串会删除对应字段
+    pub fn apply(&mut self, settings: &Settings) {
+        let root = self.document.as_table_mut();
+
+        // mpv 是必填项，留空时使用默认程序
+        set_str(root, "mpv", &settings.mpv, true);
+        set_str(root, "proxy", &settings.proxy, false);
+        set_str(root, "useragent", &settings.useragent, false);
+        set_str(root, "device_name", &settings.device_name, false);
+        set_str(root, "device_id", &settings.device_id, false);
+        set_str(root, "secret_store", &settings.secret_store, false);
+
+        if settings.mpv_args.is_empty() {
+            root.remove("mpv_args");
+        } else {
+            let args: Array = settings.mpv_args.iter().map(String::as_str).collect();
+            set_value(root, "mpv_args", Value::Array(args));
+        }
+
+        let existing = root
+            .remove("servers")
+            .and_then(|item| item.into_array_of_tables().ok())
+            .unwrap_or_default();
+
+        let mut servers = ArrayOfTables::new();
+        for server in &settings.servers {
+            let mut table = server
+                .index
+                .and_then(|index| existing.get(index).cloned())
+                .unwrap_or_default();
+
+            set_str(&mut table, "name", &server.name, true);
+            set_str(&mut table, "url", &server.url, true);
+            set_str(&mut table, "api_key", &server.api_key, false);
+            set_str(&mut table, "username", &server.username, false);
+            set_str(&mut table, "password", &server.password, false);
+            set_str(
+                &mut table,
+                "password_command",
+                &server.password_command,
+                false,
+            );
+            if server.quick_connect {
+                set_value(&mut table, "quick_connect", Value::from(true));
+            } else {
+                table.remove("quick_connect");
+            }
+
+            servers.push(table);
+        }
+
+        if !servers.is_empty() {
+            root.insert("servers", Item::ArrayOfTables(servers));
+        }
+    }
+
+    // 先写入临时文件再替换，原文件备份为 .bak
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // 配置中有密码和 token，临时文件沿用原文件的权限，没有原文件时只允许当前用户读写
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            let mode =
+                fs::metadata(&self.path).map_or(0o600, |meta| meta.permissions().mode() & 0o777);
+            options.mode(mode);
+        }
+
+        let tmp_path = self.path.with_extension("toml.tmp");
+        let _ = fs::remove_file(&tmp_path);
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(self.document.to_string().as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+
+        if self.path.exists() {
+            fs::copy(&self.path, self.path.with_extension("toml.bak"))?;
+        }
+
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn get_str(table: &Table, key: &str) -> String {
+    table
+        .get(key)
+        .and_then(Item::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn set_str(table: &mut Table, key: &str, text: &str, required: bool) {
+    if text.is_empty() && !required {
+        table.remove(key);
+    } else {
+        set_value(table, key, Value::from(text));
+    }
+}
+
+// 替换值时保留行尾注释
+fn set_value(table: &mut Table, key: &str, new: Value) {
+    match table.get_mut(key).and_then(Item::as_value_mut) {
+        Some(current) => {
+            let decor = current.decor().clone();
+            *current = new;
+            *current.decor_mut() = decor;
+        }
+        None => {
+            table.insert(key, value(new));
+        }
+    }
+}
+
+// 检查配置，返回所有错误
+pub fn validate(settings: &Settings) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if !settings.proxy.is_empty()
+        && !["http://", "https://", "socks5://", "socks5h://"]
+            .iter()
+            .any(|scheme| settings.proxy.starts_with(scheme))
+    {
+        errors.push("proxy must start with http://, https:// or socks5://".to_string());
+    }
+
+    if !settings.secret_store.is_empty() && !SECRET_STORES.contains(&settings.secret_store.as_str())
+    {
+        errors.push(format!(
+            "secret_store must be one of {}",
+            SECRET_STORES.join(", ")
+        ));
+    }
+
+    for (i, server) in settings.servers.iter().enumerate() {
+        let label = match server.name.is_empty() {
+            true => format!("server #{}", i + 1),
+            false => format!("server \"{}\"", server.name),
+        };
+
+        if server.name.is_empty() {
+            errors.push(format!("{}: name is required", label));
+        } else if settings.servers[..i].iter().any(|s| s.name == server.name) {
+            errors.push(format!("{}: name is duplicated", label));
+        }
+
+        if !server.url.starts_with("http://") && !server.url.starts_with("https://") {
+            errors.push(format!(
+                "{}: url must start with http:// or https://",
+                label
+            ));
+        }
+
+        if !server.password.is_empty() && !server.password_command.is_empty() {
+            errors.push(format!(
+                "{}: set either password or password_command, not both",
+                label
+            ));
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigFile;
+    use std::path::PathBuf;
+
+    const COMMENTED: &str = r#"# mpv-handler 配置
+mpv = "/usr/bin/mpv" # 播放器路径
+proxy = "http://127.0.0.1:7890"
+single_instance = "append"
+mpv_args = ["--fs"]
+
+# 继续播放
+[resume]
+mode = "ask" # 询问
+ask_timeout = 10
+
+[[servers]]
+# 家里的服务器
+name = "home"
+url = "https://emby.example.com"
+api_key = "xxxxxxxx"
+max_streaming_bitrate = 8000000
+
+[generic]
+hosts = ["example.com"]
+"#;
+
+    fn config(text: &str) -> ConfigFile {
+        ConfigFile {
+            path: PathBuf::from("mpv-handler.toml"),
+            document: text.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn apply_unchanged_settings_keeps_the_file() {
+        let mut config = config(COMMENTED);
+        let settings = config.settings();
+        config.apply(&settings);
+
+        assert_eq!(config.document.to_string(), COMMENTED);
+    }
+
+    #[test]
+    fn apply_keeps_comments_and_unknown_keys() {
+        let mut config = config(COMMENTED);
+        let mut settings = config.settings();
+        settings.proxy = String::new();
+        settings.servers[0].url = "https://emby.example.org".to_string();
+        config.apply(&settings);
+
+        let text = config.document.to_string();
+        assert!(!text.contains("proxy"));
+        assert!(text.contains("url = \"https://emby.example.org\""));
+        for kept in [
+            "# mpv-handler 配置",
+            "mpv = \"/usr/bin/mpv\" # 播放器路径",
+            "single_instance = \"append\"",
+            "# 继续播放\n[resume]",
+            "mode = \"ask\" # 询问",
+            "# 家里的服务器",
+            "max_streaming_bitrate = 8000000",
+            "[generic]\nhosts = [\"example.com\"]",
+        ] {
+            assert!(text.contains(kept), "{:?} missing in\n{}", kept, text);
+        }
+    }
+}
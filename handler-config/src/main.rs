@@ -1,13 +1,26 @@
 #![windows_subsystem = "windows"]
 use eframe::egui;
+#[cfg(target_os = "linux")]
+use handler_common::desktop;
+use handler_common::mpv;
+use handler_config::config::{
+    config_path, validate, ConfigFile, ServerEntry, Settings, SECRET_STORES,
+};
 use rfd::FileDialog;
+#[cfg(not(target_os = "linux"))]
 use std::fs::File;
-use std::io::{self, Write};
+use std::io;
+#[cfg(not(target_os = "linux"))]
+use std::io::Write;
+#[cfg(not(target_os = "linux"))]
 use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver};
 
 fn main() -> eframe::Result<()> {
     let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([500.0, 350.0]),
+        viewport: egui::ViewportBuilder::default().with_inner_size([600.0, 700.0]),
         ..Default::default()
     };
 
@@ -21,8 +34,22 @@ fn main() -> eframe::Result<()> {
 #[derive(Default)]
 struct ConfigApp {
     handler_path: String,
-    mpv_path: String,
+    settings: Settings,
+    // mpv_args 编辑框，每行一个参数
+    mpv_args: String,
+    // 找到的 mpv 及其版本
+    mpv_builds: Vec<(String, String)>,
+    detecting: Option<Receiver<Vec<(String, String)>>>,
+    config: Option<ConfigFile>,
+    errors: Vec<String>,
     status_message: String,
+    // 诊断用的 mpv:// 链接和结果
+    test_url: String,
+    diagnostics: String,
+    diagnosing: Option<Receiver<String>>,
+    // 已注册 mpv:// 的其他程序
+    #[cfg(target_os = "linux")]
+    conflict: Option<String>,
 }
 
 impl eframe::App for ConfigApp {
@@ -30,79 +57,291 @@ impl eframe::App for ConfigApp {
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("MPV Handler Config Generator");
 
-            ui.add_space(20.0);
-            if ui.button("Choose handler path").clicked() {
-                if let Some(path) = FileDialog::new()
-                    .add_filter("exe file", &["exe"])
-                    .pick_file()
-                {
-                    self.handler_path = path.display().to_string();
-                    self.status_message.clear();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.add_space(20.0);
+                if ui.button("Choose handler path").clicked() {
+                    if let Some(path) = file_dialog().pick_file() {
+                        self.handler_path = path.display().to_string();
+                        self.status_message.clear();
+                        self.load_config();
+                    }
                 }
-            }
 
-            ui.add_space(10.0);
-            ui.horizontal(|ui| {
-                ui.label("Current handler path:");
-                ui.text_edit_singleline(&mut self.handler_path);
-            });
+                ui.add_space(10.0);
+                ui.horizontal(|ui| {
+                    ui.label("Current handler path:");
+                    ui.text_edit_singleline(&mut self.handler_path);
+                });
 
-            ui.add_space(10.0);
+                ui.add_space(10.0);
 
-            if ui.button("Choose mpv path").clicked() {
-                if let Some(path) = FileDialog::new()
-                    .add_filter("exe file", &["exe"])
-                    .pick_file()
-                {
-                    self.mpv_path = path.display().to_string();
-                    self.status_message.clear();
+                if ui.button("Choose mpv path").clicked() {
+                    if let Some(path) = file_dialog().pick_file() {
+                        self.settings.mpv = path.display().to_string();
+                        self.status_message.clear();
+                    }
                 }
-            }
 
-            ui.add_space(10.0);
-            ui.horizontal(|ui| {
-                ui.label("Current mpv path:");
-                ui.text_edit_singleline(&mut self.mpv_path);
-            });
+                ui.add_space(10.0);
+                ui.horizontal(|ui| {
+                    ui.label("Current mpv command:");
+                    ui.text_edit_singleline(&mut self.settings.mpv);
+                });
 
-            ui.add_space(20.0);
-            ui.separator();
-            ui.add_space(20.0);
+                if let Some(builds) = self.detecting.as_ref().and_then(|rx| rx.try_recv().ok()) {
+                    self.mpv_builds = builds;
+                    self.detecting = None;
+                }
+
+                ui.add_space(10.0);
+                ui.horizontal(|ui| {
+                    ui.label("Detected mpv:");
+                    if ui
+                        .add_enabled(self.detecting.is_none(), egui::Button::new("Detect"))
+                        .clicked()
+                    {
+                        self.detecting = Some(detect_mpv(ctx));
+                    }
+                });
+                if self.detecting.is_some() {
+                    ui.label("Detecting...");
+                } else if self.mpv_builds.is_empty() {
+                    ui.label("No mpv found");
+                }
+                for (command, version) in &self.mpv_builds {
+                    ui.selectable_value(
+                        &mut self.settings.mpv,
+                        command.clone(),
+                        format!("{}  ({})", command, version),
+                    );
+                }
+
+                ui.add_space(20.0);
+                ui.separator();
+                ui.add_space(10.0);
+
+                egui::Grid::new("settings").num_columns(2).show(ui, |ui| {
+                    ui.label("Proxy:");
+                    ui.text_edit_singleline(&mut self.settings.proxy);
+                    ui.end_row();
+
+                    ui.label("User agent:");
+                    ui.text_edit_singleline(&mut self.settings.useragent);
+                    ui.end_row();
+
+                    ui.label("Extra mpv args:");
+                    ui.text_edit_multiline(&mut self.mpv_args);
+                    ui.end_row();
+
+                    ui.label("Device name:");
+                    ui.text_edit_singleline(&mut self.settings.device_name);
+                    ui.end_row();
+
+                    ui.label("Device id:");
+                    ui.text_edit_singleline(&mut self.settings.device_id);
+                    ui.end_row();
+
+                    ui.label("Secret store:");
+                    egui::ComboBox::from_id_salt("secret_store")
+                        .selected_text(match self.settings.secret_store.is_empty() {
+                            true => "auto",
+                            false => &self.settings.secret_store,
+                        })
+                        .show_ui(ui, |ui| {
+                            for store in SECRET_STORES {
+                                ui.selectable_value(
+                                    &mut self.settings.secret_store,
+                                    store.to_string(),
+                                    store,
+                                );
+                            }
+                        });
+                    ui.end_row();
+                });
+
+                ui.add_space(10.0);
+                ui.label("Servers:");
+
+                let mut removed = None;
+                for (i, server) in self.settings.servers.iter_mut().enumerate() {
+                    let title = match server.name.is_empty() {
+                        true => format!("Server #{}", i + 1),
+                        false => server.name.clone(),
+                    };
+                    egui::CollapsingHeader::new(title)
+                        .id_salt(("server", i))
+                        .show(ui, |ui| {
+                            egui::Grid::new(("server_grid", i))
+                                .num_columns(2)
+                                .show(ui, |ui| {
+                                    for (label, field) in [
+                                        ("Name:", &mut server.name),
+                                        ("URL:", &mut server.url),
+                                        ("API key:", &mut server.api_key),
+                                        ("Username:", &mut server.username),
+                                        ("Password command:", &mut server.password_command),
+                                    ] {
+                                        ui.label(label);
+                                        ui.text_edit_singleline(field);
+                                        ui.end_row();
+                                    }
+
+                                    ui.label("Password:");
+                                    ui.add(
+                                        egui::TextEdit::singleline(&mut server.password)
+                                            .password(true),
+                                    );
+                                    ui.end_row();
+                                });
+                            ui.checkbox(&mut server.quick_connect, "Quick Connect");
+                            if ui.button("Remove").clicked() {
+                                removed = Some(i);
+                            }
+                        });
+                }
+                if let Some(i) = removed {
+                    self.settings.servers.remove(i);
+                }
+
+                if ui.button("Add server").clicked() {
+                    self.settings.servers.push(ServerEntry::default());
+                }
+
+                ui.add_space(20.0);
+                ui.separator();
+                ui.add_space(20.0);
 
-            ui.horizontal(|ui| {
                 let has_handler = !self.handler_path.is_empty();
-                let has_mpv = !self.mpv_path.is_empty();
 
-                if ui
-                    .add_enabled(has_handler, egui::Button::new("Generate registry"))
-                    .clicked()
-                {
-                    if let Err(err) = self.generate_reg_file(&self.handler_path) {
-                        self.status_message = format!("Generate reg Failed: {}", err);
-                    } else {
-                        self.status_message = "Generate reg Success!".to_string();
+                ui.horizontal(|ui| {
+                    #[cfg(not(target_os = "linux"))]
+                    if ui
+                        .add_enabled(has_handler, egui::Button::new("Generate registry"))
+                        .clicked()
+                    {
+                        if let Err(err) = self.generate_reg_file(&self.handler_path) {
+                            self.status_message = format!("Generate reg Failed: {}", err);
+                        } else {
+                            self.status_message = "Generate reg Success!".to_string();
+                        }
+                    }
+
+                    #[cfg(target_os = "linux")]
+                    if ui
+                        .add_enabled(has_handler, egui::Button::new("Install desktop entry"))
+                        .clicked()
+                    {
+                        self.status_message = match self.install_desktop_entry(&self.handler_path) {
+                            Ok(Some(replaced)) => {
+                                format!("Install Success! Replaced {}", replaced)
+                            }
+                            Ok(None) => "Install Success!".to_string(),
+                            Err(err) => format!("Install Failed: {}", err),
+                        };
+                        self.refresh_conflict();
                     }
-                }
+
+                    #[cfg(target_os = "linux")]
+                    if ui.button("Uninstall").clicked() {
+                        self.status_message =
+                            match desktop::Paths::user().and_then(|p| desktop::uninstall(&p)) {
+                                Ok(true) => "Uninstall Success!".to_string(),
+                                Ok(false) => "Nothing to uninstall".to_string(),
+                                Err(err) => format!("Uninstall Failed: {}", err),
+                            };
+                        self.refresh_conflict();
+                    }
+
+                    if ui
+                        .add_enabled(
+                            has_handler || cfg!(not(windows)),
+                            egui::Button::new("Save toml"),
+                        )
+                        .clicked()
+                    {
+                        self.status_message = match self.save_config() {
+                            Ok(Some(path)) => format!("Saved {}", path.display()),
+                            Ok(None) => "Please fix the errors above".to_string(),
+                            Err(err) => format!("Save toml Failed: {}", err),
+                        };
+                    }
+                });
+
+                ui.add_space(20.0);
+                ui.separator();
+                ui.add_space(10.0);
+
+                ui.horizontal(|ui| {
+                    ui.label("Test url:");
+                    ui.text_edit_singleline(&mut self.test_url)
+                        .on_hover_text("Optional mpv:// link to check, uses the saved config");
+                });
 
                 if ui
-                    .add_enabled(has_handler && has_mpv, egui::Button::new("Generate toml"))
+                    .add_enabled(
+                        has_handler && self.diagnosing.is_none(),
+                        egui::Button::new("Test connection"),
+                    )
                     .clicked()
                 {
-                    if let Err(err) = self.generate_toml(&self.handler_path, &self.mpv_path) {
-                        self.status_message = format!("Generate toml Failed: {}", err);
-                    } else {
-                        self.status_message = "Generate toml Success!".to_string();
-                    }
+                    self.diagnostics = "Running...".to_string();
+                    self.diagnosing = Some(self.run_doctor(ctx));
                 }
-            });
 
-            if !self.status_message.is_empty() {
-                ui.label(&self.status_message);
-            }
+                if let Some(result) = self.diagnosing.as_ref().and_then(|rx| rx.try_recv().ok()) {
+                    self.diagnostics = result;
+                    self.diagnosing = None;
+                }
+
+                if !self.diagnostics.is_empty() {
+                    ui.monospace(&self.diagnostics);
+                }
+
+                for error in &self.errors {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+
+                #[cfg(target_os = "linux")]
+                if let Some(conflict) = &self.conflict {
+                    ui.label(format!("mpv:// is currently handled by {}", conflict));
+                }
+
+                if !self.status_message.is_empty() {
+                    ui.label(&self.status_message);
+                }
+            });
         });
     }
 }
 
+// Windows 下只显示 exe 文件
+fn file_dialog() -> FileDialog {
+    #[cfg(windows)]
+    return FileDialog::new().add_filter("exe file", &["exe"]);
+    #[cfg(not(windows))]
+    return FileDialog::new();
+}
+
+// 在后台查找 mpv 并获取版本
+fn detect_mpv(ctx: &egui::Context) -> Receiver<Vec<(String, String)>> {
+    let (tx, rx) = mpsc::channel();
+
+    let ctx = ctx.clone();
+    std::thread::spawn(move || {
+        let builds = mpv::candidates()
+            .into_iter()
+            .map(|command| {
+                let version = mpv::version(&command).unwrap_or_else(|| "not runnable".to_string());
+                (command, version)
+            })
+            .collect();
+        let _ = tx.send(builds);
+        ctx.request_repaint();
+    });
+
+    rx
+}
+
 impl ConfigApp {
     fn new(cc: &eframe::CreationContext<'_>) -> Self {
         // Set style
@@ -124,9 +363,110 @@ impl ConfigApp {
         .into();
         cc.egui_ctx.set_style(style);
 
-        Default::default()
+        let mut app = ConfigApp {
+            detecting: Some(detect_mpv(&cc.egui_ctx)),
+            ..Default::default()
+        };
+        // Windows 下配置文件和 handler 在同一目录，选择 handler 后再读取
+        #[cfg(not(windows))]
+        app.load_config();
+        #[cfg(target_os = "linux")]
+        app.refresh_conflict();
+
+        app
+    }
+
+    // 读取现有配置，失败时保留当前编辑内容
+    fn load_config(&mut self) {
+        let Some(path) = config_path(&self.handler_path) else {
+            return;
+        };
+
+        match ConfigFile::load(&path) {
+            Ok(config) => {
+                self.settings = config.settings();
+                self.mpv_args = self.settings.mpv_args.join("\n");
+                self.config = Some(config);
+            }
+            Err(err) => {
+                self.status_message = format!("Load {} Failed: {}", path.display(), err);
+            }
+        }
+    }
+
+    // 校验通过后保存，返回保存的路径
+    fn save_config(&mut self) -> io::Result<Option<PathBuf>> {
+        self.settings.mpv_args = self
+            .mpv_args
+            .lines()
+            .map(str::trim)
+            .filter(|arg| !arg.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        self.errors = validate(&self.settings);
+        if !self.errors.is_empty() {
+            return Ok(None);
+        }
+
+        let path = config_path(&self.handler_path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Config path not found"))?;
+
+        let mut config = match self.config.take() {
+            Some(config) if config.path == path => config,
+            _ => ConfigFile::load(&path)?,
+        };
+        config.apply(&self.settings);
+        config.save()?;
+
+        // 保存后 [[servers]] 的顺序与编辑内容一致
+        for (i, server) in self.settings.servers.iter_mut().enumerate() {
+            server.index = Some(i);
+        }
+        self.config = Some(config);
+
+        Ok(Some(path))
+    }
+
+    // 在后台运行 mpv-handler doctor，结束后返回输出
+    fn run_doctor(&self, ctx: &egui::Context) -> Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        let mut command = Command::new(&self.handler_path);
+        command.arg("doctor");
+        if !self.test_url.trim().is_empty() {
+            command.arg(self.test_url.trim());
+        }
+
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let result = match command.output() {
+                Ok(output) => format!(
+                    "{}{}",
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                Err(err) => format!("Failed to run mpv-handler: {}", err),
+            };
+            let _ = tx.send(result);
+            ctx.request_repaint();
+        });
+
+        rx
     }
 
+    #[cfg(target_os = "linux")]
+    fn refresh_conflict(&mut self) {
+        self.conflict = desktop::Paths::user()
+            .and_then(|paths| desktop::conflict(&paths))
+            .unwrap_or_default();
+    }
+
+    #[cfg(target_os = "linux")]
+    fn install_desktop_entry(&self, exe_path: &str) -> io::Result<Option<String>> {
+        desktop::install(&desktop::Paths::user()?, exe_path)
+    }
+
+    #[cfg(not(target_os = "linux"))]
     fn generate_reg_file(&self, exe_path: &str) -> io::Result<()> {
         let exe_path_escaped = exe_path.replace("\\", "\\\\");
 
@@ -153,16 +493,4 @@ impl ConfigApp {
 
         Ok(())
     }
-
-    fn generate_toml(&self, handler_path: &str, mpv_path: &str) -> io::Result<()> {
-        let toml_path = Path::new(handler_path)
-            .parent()
-            .unwrap()
-            .join("mpv-handler.toml");
-        let mut file = File::create(toml_path)?;
-        let mpv = format!(r#"mpv = "{}""#, mpv_path.replace("\\", "\\\\"));
-        file.write_all(mpv.as_bytes())?;
-
-        Ok(())
-    }
 }
@@ -0,0 +1,123 @@
+use crate::config::{Config, Server};
+use crate::log;
+use crate::network::extractor::host_of;
+use crate::network::request;
+use crate::secret;
+use anyhow::{anyhow, Result};
+use std::io::IsTerminal;
+use std::process::Command;
+use std::time::Duration;
+use url::Url;
+
+// 链接中没有 api_key 时，按域名查找 token
+pub async fn lookup(host: &str) -> Result<String> {
+    if let Some(server) = Config::load()?.find_server(host) {
+        return token(server).await;
+    }
+
+    secret::get(host)?.ok_or_else(|| anyhow!("No api_key in URL and no token stored for {}", host))
+}
+
+// 保存链接中带有的 token，之后回传未送达的进度时用 lookup 找到
+// 失败时只记录日志，不影响播放
+pub fn remember(host: &str, api_key: &str) {
+    if secret::get(host).ok().flatten().as_deref() == Some(api_key) {
+        return;
+    }
+    if let Err(e) = secret::set(host, api_key) {
+        log!("保存 {} 的 token 失败: {}", host, e);
+    }
+}
+
+// 获取服务器的 token：优先使用配置中的 api_key，其次使用密钥存储，最后登录
+pub async fn token(server: &Server) -> Result<String> {
+    if let Some(api_key) = server.api_key.as_ref().filter(|key| !key.is_empty()) {
+        return Ok(api_key.clone());
+    }
+
+    let host = server.host();
+    let key = host_of(&Url::parse(&server.url)?)?;
+
+    if let Some(token) = secret::get(&key)? {
+        if request::check_token(host, &token).await? {
+            return Ok(token);
+        }
+        log!("{} 的登录已失效，重新登录", server.name);
+    }
+
+    let access_token = login(server).await?;
+    secret::set(&key, &access_token)?;
+
+    Ok(access_token)
+}
+
+async fn login(server: &Server) -> Result<String> {
+    let host = server.host();
+
+    if let Some(username) = &server.username {
+        let password = password(server)?;
+        return request::authenticate_by_name(host, username, &password).await;
+    }
+
+    // 代码只能输出到终端，由浏览器或图形界面启动时看不到，只在终端中使用
+    if server.quick_connect && !std::io::stdout().is_terminal() {
+        return Err(anyhow!(
+            "Quick Connect for {} needs a terminal, log in once with `mpv-handler play --item <id> --server {}`",
+            server.name,
+            server.name
+        ));
+    }
+
+    if server.quick_connect {
+        let (secret, code) = request::quick_connect_initiate(host).await?;
+        log!("请在已登录的客户端中输入 Quick Connect 代码: {}", code);
+
+        // 最多等待 5 分钟
+        for _ in 0..60 {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            if request::quick_connect_authenticated(host, &secret).await? {
+                return request::authenticate_with_quick_connect(host, &secret).await;
+            }
+        }
+
+        return Err(anyhow!("Quick Connect timed out"));
+    }
+
+    Err(anyhow!(
+        "No api_key, username or quick_connect configured for {}",
+        server.name
+    ))
+}
+
+fn password(server: &Server) -> Result<String> {
+    if let Some(command) = &server.password_command {
+        #[cfg(windows)]
+        let output = Command::new("cmd").args(["/C", command]).output()?;
+        #[cfg(unix)]
+        let output = Command::new("sh").args(["-c", command]).output()?;
+
+        if !output.status.success() {
+            return Err(anyhow!("password_command failed, {}", output.status));
+        }
+
+        let password = String::from_utf8(output.stdout)?;
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    Ok(server.password.clone().unwrap_or_default())
+}
+
+// 保存 token 到密钥存储，token 为空时删除
+pub fn store_token(server_url: &str, token: &str) -> Result<()> {
+    let key = host_of(&Url::parse(server_url)?)?;
+
+    if token.is_empty() {
+        secret::delete(&key)?;
+        log!("已删除 {} 的 token", key);
+    } else {
+        secret::set(&key, token)?;
+        log!("已保存 {} 的 token", key);
+    }
+
+    Ok(())
+}
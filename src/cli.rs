@@ -0,0 +1,143 @@
+use crate::{history, register};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use std::fmt;
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(
+    name = "mpv-handler",
+    version,
+    about = "Play Emby videos with mpv and report the progress back",
+    args_conflicts_with_subcommands = true,
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    /// mpv://play/... or mpv://item/<server>/<item-id> link opened by the browser
+    pub url: Option<String>,
+
+    #[command(flatten)]
+    pub options: Options,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Args, Clone, Copy, Default)]
+pub struct Options {
+    /// Print the mpv command line and API calls without launching mpv
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Do not report playback progress to the server
+    #[arg(long, global = true)]
+    pub no_report: bool,
+
+    /// Print more details and let mpv log at its default level
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Play an http(s) stream url without base64 encoding it, or an item by id
+    #[command(group(ArgGroup::new("source").required(true).args(["url", "item"])))]
+    Play {
+        /// Emby stream url, e.g. https://emby.example.com/emby/videos/123/stream.mkv?MediaSourceId=...
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Item id, the stream url is built from the server given by --server
+        #[arg(long, requires = "server")]
+        item: Option<String>,
+
+        /// External subtitle url
+        #[arg(long, conflicts_with = "item")]
+        subfile: Option<String>,
+
+        /// Server name or url in the config to take the token from
+        #[arg(long)]
+        server: Option<String>,
+    },
+    /// Download an item for offline playback, resuming an interrupted download
+    #[command(group(ArgGroup::new("source").required(true).args(["url", "item"])))]
+    Download {
+        /// mpv://play/... link or Emby stream url
+        url: Option<String>,
+
+        /// Item id, downloaded from the server given by --server
+        #[arg(long, requires = "server")]
+        item: Option<String>,
+
+        /// Server name or url in the config to take the token from
+        #[arg(long)]
+        server: Option<String>,
+    },
+    /// List downloaded items, or play one and report the progress once the server is reachable
+    Offline {
+        /// Item id of a downloaded item
+        item: Option<String>,
+    },
+    /// List the local watch history
+    History(history::Filter),
+    /// Run as a cast target that can be controlled from Emby
+    Daemon,
+    /// Check the config, mpv and the server connection
+    Doctor {
+        /// mpv:// link to check
+        url: Option<String>,
+    },
+    /// Read a token from stdin and store it for the server, an empty token removes it
+    SetToken {
+        /// Server url
+        server_url: String,
+    },
+    /// Register the mpv:// protocol
+    Register(register::Options),
+    /// Remove the mpv:// protocol registration
+    Unregister(register::Options),
+}
+
+// 失败原因，决定进程的退出码
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    Parse,
+    Auth,
+    Network,
+    Player,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            Failure::Parse => "Invalid link",
+            Failure::Auth => "Authentication failed",
+            Failure::Network => "Network error",
+            Failure::Player => "Player error",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl Failure {
+    // 2 与 clap 的参数错误一致
+    fn code(self) -> u8 {
+        match self {
+            Failure::Parse => 2,
+            Failure::Auth => 3,
+            Failure::Network => 4,
+            Failure::Player => 5,
+        }
+    }
+}
+
+pub fn exit_code(e: &anyhow::Error) -> ExitCode {
+    // 请求失败可能出现在任何一步，优先按网络错误处理
+    if e.downcast_ref::<reqwest::Error>().is_some() {
+        return ExitCode::from(Failure::Network.code());
+    }
+
+    match e.downcast_ref::<Failure>() {
+        Some(failure) => ExitCode::from(failure.code()),
+        None => ExitCode::FAILURE,
+    }
+}
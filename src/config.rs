@@ -1,23 +1,69 @@
+use crate::log;
+use crate::network::extractor::host_of;
+use crate::network::property;
 use anyhow::{anyhow, Context, Result};
+use handler_common::mpv::{self, Sandbox};
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
+use url::Url;
 
 pub const DEFAULT_UA: &str = "Emby/3.2.32-17.32 (Linux;Android 13) ExoPlayerLib/2.13.2";
 
+// 向服务器报告的客户端名称和版本
+pub const CLIENT_NAME: &str = "mpv-handler";
+pub const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
 pub struct MPVClient;
 
 impl MPVClient {
     pub fn build() -> Result<Command> {
-        let mpv_command = Config::load().context("获取自定义配置失败")?.mpv;
+        let config = Config::load().context("获取自定义配置失败")?;
+
+        let command_line = MPVClient::command_line(&config);
+        log!("当前使用的MPV路径为: {}", command_line);
+
+        let mut parts = mpv::split_command(&command_line);
+        // IPC socket 所在的目录只有当前用户能访问
+        #[cfg(unix)]
+        if mpv::sandbox(&command_line).is_none() {
+            runtime_dir()?;
+        }
+        if let Some(sandbox) = mpv::sandbox(&command_line) {
+            // 沙箱中的 mpv 通过共享的运行时目录创建 IPC socket
+            if let Some(dir) = Path::new(property::ipc_server()).parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
 
-        match mpv_command.is_empty() {
-            true => Ok(Command::new(default_mpv())),
-            false => {
-                println!("当前使用的MPV路径为: {}", mpv_command);
-                Ok(Command::new(mpv_command))
+            if let (Sandbox::Flatpak(_), Some(run)) =
+                (&sandbox, parts.iter().position(|part| part == "run"))
+            {
+                let filesystem = format!("--filesystem=xdg-run/{}:create", sandbox.runtime_dir());
+                parts.insert(run + 1, filesystem);
             }
         }
+
+        let (program, args) = parts.split_first().ok_or(anyhow!("mpv command is empty"))?;
+        let mut command = Command::new(program);
+        command.args(args).args(config.mpv_args);
+
+        Ok(command)
+    }
+
+    // 配置的 mpv 是否运行在沙箱中
+    pub fn sandbox() -> Option<Sandbox> {
+        let config = Config::load().ok()?;
+        mpv::sandbox(&MPVClient::command_line(&config))
+    }
+
+    // mpv 启动命令，可以带参数，留空时自动查找
+    pub fn command_line(config: &Config) -> String {
+        match config.mpv.trim().is_empty() {
+            true => default_mpv(),
+            false => config.mpv.clone(),
+        }
     }
 }
 
@@ -26,6 +72,177 @@ pub struct Config {
     pub mpv: String,
     pub proxy: Option<String>,
     pub useragent: Option<String>,
+    // 追加到 mpv 命令行的参数
+    #[serde(default)]
+    pub mpv_args: Vec<String>,
+    #[serde(default)]
+    pub servers: Vec<Server>,
+    #[serde(default)]
+    pub secret_store: SecretStore,
+    #[serde(default)]
+    pub media_source: SourcePolicy,
+    // 超过该码率（bps）的版本改用服务器转码
+    pub max_streaming_bitrate: Option<u64>,
+    // 离线下载目录
+    pub library: Option<PathBuf>,
+    // 本地进度比服务器新时，播放前先把本地进度回传到服务器
+    #[serde(default)]
+    pub sync_local_position: bool,
+    // 收到退出信号时不关闭 mpv，只回传进度
+    #[serde(default)]
+    pub keep_player_on_signal: bool,
+    // 从浏览器打开时脱离浏览器在新会话中运行
+    #[serde(default)]
+    pub detach: bool,
+    // 单实例模式，后打开的链接交给正在运行的实例
+    pub single_instance: Option<InstanceMode>,
+    #[serde(default)]
+    pub resume: ResumePolicy,
+    // 信任的服务器，如 https://emby.example.com，[[servers]] 中的服务器总是信任
+    #[serde(default)]
+    pub trusted_servers: Vec<String>,
+    // 链接指向不信任的服务器时的处理方式
+    #[serde(default)]
+    pub unknown_servers: UnknownServers,
+    // 不是 Emby 推流链接时直接交给 mpv 播放，没有配置时拒绝
+    pub generic: Option<GenericPolicy>,
+    // 留空时使用保存在状态目录中的设备 ID
+    pub device_id: Option<String>,
+    // 留空时使用主机名
+    pub device_name: Option<String>,
+}
+
+// token 的存储位置，auto 优先使用系统密钥环，不可用时使用加密文件
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretStore {
+    #[default]
+    Auto,
+    Keyring,
+    File,
+}
+
+// 有播放进度时从哪里开始播放
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ResumePolicy {
+    pub mode: ResumeMode,
+    // remaining 策略下，剩余时间少于该秒数时从头播放
+    pub min_remaining: u64,
+    // ask 策略下等待选择的秒数，超时后继续播放
+    pub ask_timeout: u64,
+}
+
+impl Default for ResumePolicy {
+    fn default() -> Self {
+        ResumePolicy {
+            mode: ResumeMode::Always,
+            min_remaining: 300,
+            ask_timeout: 10,
+        }
+    }
+}
+
+// 链接指向不信任的服务器时，ask 在 mpv 中确认，deny 拒绝，allow 直接播放
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownServers {
+    #[default]
+    Ask,
+    Deny,
+    Allow,
+}
+
+// 允许直接播放的普通链接
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct GenericPolicy {
+    // 允许的协议，如 https、magnet、ytdl
+    pub schemes: Vec<String>,
+    // http(s) 链接允许的主机，同时允许其子域名
+    pub hosts: Vec<String>,
+    // 播放普通链接时追加的 mpv 参数
+    pub args: Vec<String>,
+}
+
+impl Default for GenericPolicy {
+    fn default() -> Self {
+        GenericPolicy {
+            schemes: vec!["https".to_string()],
+            hosts: Vec::new(),
+            args: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResumeMode {
+    #[default]
+    Always,
+    Never,
+    Ask,
+    Remaining,
+}
+
+// 单实例模式下如何播放转交的条目
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceMode {
+    // 替换正在播放的条目
+    Replace,
+    // 加入 mpv 的播放列表
+    Append,
+}
+
+// 条目有多个版本时的选择策略
+#[derive(Debug, Default, Deserialize)]
+pub struct SourcePolicy {
+    // 优先选择名称中包含该文本的版本，如 "4K"
+    pub name: Option<String>,
+    // 优先选择的视频编码，如 "hevc"
+    pub codec: Option<String>,
+    // 超过该码率（bps）的版本不优先选择
+    pub max_bitrate: Option<u64>,
+    // 优先选择分辨率最高的版本
+    #[serde(default)]
+    pub highest_resolution: bool,
+    // 启动后在 mpv 中选择版本
+    #[serde(default)]
+    pub picker: bool,
+}
+
+impl SourcePolicy {
+    // 是否配置了选择条件，未配置时沿用链接中的版本，picker 不算选择条件
+    pub fn is_set(&self) -> bool {
+        self.name.is_some()
+            || self.codec.is_some()
+            || self.max_bitrate.is_some()
+            || self.highest_resolution
+    }
+}
+
+// 服务器配置，api_key 留空时使用账号登录
+#[derive(Debug, Deserialize)]
+pub struct Server {
+    pub name: String,
+    pub url: String,
+    pub api_key: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // 输出密码的命令，如 `pass show emby`
+    pub password_command: Option<String>,
+    // 使用 Jellyfin Quick Connect 登录
+    #[serde(default)]
+    pub quick_connect: bool,
+    // 覆盖全局的 max_streaming_bitrate
+    pub max_streaming_bitrate: Option<u64>,
+}
+
+impl Server {
+    pub fn host(&self) -> &str {
+        self.url.trim_end_matches('/')
+    }
 }
 
 impl Default for Config {
@@ -34,6 +251,22 @@ impl Default for Config {
             mpv: default_mpv(),
             proxy: None,
             useragent: Some(DEFAULT_UA.to_string()),
+            mpv_args: Vec::new(),
+            servers: Vec::new(),
+            secret_store: SecretStore::default(),
+            media_source: SourcePolicy::default(),
+            max_streaming_bitrate: None,
+            library: None,
+            sync_local_position: false,
+            keep_player_on_signal: false,
+            detach: false,
+            single_instance: None,
+            resume: ResumePolicy::default(),
+            trusted_servers: Vec::new(),
+            unknown_servers: UnknownServers::default(),
+            generic: None,
+            device_id: None,
+            device_name: None,
         }
     }
 }
@@ -51,6 +284,31 @@ impl Config {
 
         Ok(Config::default())
     }
+
+    // 服务器的码率上限，服务器没有单独配置时使用全局配置
+    pub fn max_streaming_bitrate(&self, host: &str) -> Option<u64> {
+        self.find_server(host)
+            .and_then(|server| server.max_streaming_bitrate)
+            .or(self.max_streaming_bitrate)
+    }
+
+    // 按名称或地址查找服务器配置
+    pub fn server(&self, name_or_url: &str) -> Option<&Server> {
+        self.servers
+            .iter()
+            .find(|server| server.name == name_or_url)
+            .or_else(|| {
+                let url = Url::parse(name_or_url).ok()?;
+                self.find_server(&host_of(&url).ok()?)
+            })
+    }
+
+    // 按 scheme://host 查找服务器配置
+    pub fn find_server(&self, host: &str) -> Option<&Server> {
+        self.servers.iter().find(|server| {
+            Url::parse(&server.url).is_ok_and(|url| host_of(&url).is_ok_and(|h| h == host))
+        })
+    }
 }
 
 // 获取 config.toml 路径
@@ -69,10 +327,148 @@ fn config_path() -> Result<PathBuf> {
     Ok(config_path)
 }
 
-// 设置 mpv 默认程序
-fn default_mpv() -> String {
+// 获取状态文件目录
+pub fn state_dir() -> Result<PathBuf> {
+    #[cfg(windows)]
+    let state_dir = dirs::data_local_dir()
+        .ok_or_else(|| anyhow!("Failed to get local data dir"))?
+        .join("mpv-handler");
+    #[cfg(unix)]
+    let state_dir = dirs::state_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local/state")))
+        .ok_or_else(|| anyhow!("Failed to get home dir"))?
+        .join("mpv-handler");
+
+    std::fs::create_dir_all(&state_dir)?;
+
+    Ok(state_dir)
+}
+
+// 获取运行时文件目录，用于 pidfile、socket 等只在本次登录期间有效的文件
+// 没有 XDG_RUNTIME_DIR 时在临时目录下创建只有当前用户能访问的目录
+pub fn runtime_dir() -> Result<PathBuf> {
+    let runtime_dir = runtime_path();
+    #[cfg(unix)]
+    if dirs::runtime_dir().is_none() {
+        private_dir(&runtime_dir)?;
+    }
+
+    Ok(runtime_dir)
+}
+
+// 运行时目录的路径，不创建目录
+pub fn runtime_path() -> PathBuf {
     #[cfg(windows)]
-    return "mpv.exe".to_string();
+    let runtime_dir = std::env::temp_dir();
+    #[cfg(unix)]
+    let runtime_dir = dirs::runtime_dir().unwrap_or_else(|| {
+        let uid = unsafe { libc::getuid() };
+        std::env::temp_dir().join(format!("mpv-handler-{}", uid))
+    });
+
+    runtime_dir
+}
+
+// /tmp 中的目录可能被其他用户抢先创建或换成符号链接，检查所有者和权限
+#[cfg(unix)]
+fn private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to create {}", dir.display())),
+    }
+
+    let meta = std::fs::symlink_metadata(dir)?;
+    let uid = unsafe { libc::getuid() };
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "Runtime directory {} is not private to the current user",
+            dir.display()
+        ));
+    }
+
+    Ok(())
+}
+
+// 获取离线下载目录，默认为系统视频目录下的 mpv-handler
+pub fn library_dir() -> Result<PathBuf> {
+    let library = match Config::load()?.library {
+        Some(library) => library,
+        None => dirs::video_dir()
+            .or_else(dirs::data_dir)
+            .ok_or_else(|| anyhow!("Failed to get video dir"))?
+            .join("mpv-handler"),
+    };
+
+    std::fs::create_dir_all(&library)?;
+
+    Ok(library)
+}
+
+// 获取设备 ID，首次运行时生成并保存，保证每次启动都是同一台设备
+pub fn device_id() -> Result<&'static str> {
+    static DEVICE_ID: OnceLock<String> = OnceLock::new();
+
+    if let Some(id) = DEVICE_ID.get() {
+        return Ok(id);
+    }
+
+    let id = match Config::load()?.device_id.filter(|id| !id.is_empty()) {
+        Some(id) => id,
+        None => {
+            let path = state_dir()?.join("device_id");
+            match std::fs::read_to_string(&path) {
+                Ok(id) if !id.trim().is_empty() => id.trim().to_string(),
+                _ => {
+                    let id = uuid::Uuid::new_v4().to_string();
+                    std::fs::write(&path, &id).context("Failed to save device id")?;
+                    id
+                }
+            }
+        }
+    };
+
+    Ok(DEVICE_ID.get_or_init(|| id))
+}
+
+// 获取设备名称
+pub fn device_name() -> String {
+    if let Some(name) = Config::load()
+        .ok()
+        .and_then(|config| config.device_name)
+        .filter(|name| !name.is_empty())
+    {
+        return name;
+    }
+
+    // 尝试在类 Unix 系统上获取主机名，大多数发行版不会导出 HOSTNAME
     #[cfg(unix)]
-    return "mpv".to_string();
+    let hostname = std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok());
+
+    // 如果是 Windows 系统，使用 "COMPUTERNAME" 环境变量
+    #[cfg(windows)]
+    let hostname = std::env::var("COMPUTERNAME").ok();
+
+    hostname.unwrap_or_else(|| CLIENT_NAME.to_string())
+}
+
+// 自动查找 mpv，找不到时使用 PATH 中的 mpv
+fn default_mpv() -> String {
+    static DEFAULT_MPV: OnceLock<String> = OnceLock::new();
+    DEFAULT_MPV
+        .get_or_init(|| {
+            mpv::find().unwrap_or_else(|| {
+                #[cfg(windows)]
+                return "mpv.exe".to_string();
+                #[cfg(unix)]
+                return "mpv".to_string();
+            })
+        })
+        .clone()
 }
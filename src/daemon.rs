@@ -0,0 +1,305 @@
+use crate::auth;
+use crate::cli::Options;
+use crate::config::{device_id, Config, Server};
+use crate::log;
+use crate::network::{property, request};
+use crate::player;
+use crate::shutdown;
+use crate::ticks::Ticks;
+use anyhow::{anyhow, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+// 服务器下发的远程控制命令
+enum Command {
+    Play {
+        host: String,
+        api_key: String,
+        user_id: String,
+        item_ids: Vec<String>,
+        start_index: usize,
+        start_ticks: Option<Ticks>,
+        media_source_id: Option<String>,
+    },
+    Playstate {
+        command: String,
+        seek_ticks: Option<Ticks>,
+    },
+    General {
+        name: String,
+        arguments: Value,
+    },
+}
+
+// 正在进行的播放队列
+struct Playback {
+    handle: JoinHandle<()>,
+    cancelled: Arc<AtomicBool>,
+}
+
+pub async fn run() -> Result<()> {
+    let servers = Config::load()?.servers;
+
+    if servers.is_empty() {
+        return Err(anyhow!("No [[servers]] configured for daemon mode"));
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    for server in servers {
+        tokio::spawn(listen(server, tx.clone()));
+    }
+    drop(tx);
+
+    let mut current: Option<Playback> = None;
+    let mut signal = std::pin::pin!(shutdown::signal());
+
+    loop {
+        let command = tokio::select! {
+            command = rx.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            _ = &mut signal => {
+                // 播放任务同样收到了信号，会自己回传 Stop 并关闭 mpv
+                if let Some(playback) = current.take() {
+                    playback.cancelled.store(true, Ordering::SeqCst);
+                    let _ = tokio::time::timeout(shutdown::TIMEOUT * 3, playback.handle).await;
+                }
+                break;
+            }
+        };
+
+        match command {
+            Command::Play {
+                host,
+                api_key,
+                user_id,
+                item_ids,
+                start_index,
+                start_ticks,
+                media_source_id,
+            } => {
+                stop(&mut current).await;
+
+                let cancelled = Arc::new(AtomicBool::new(false));
+                let flag = cancelled.clone();
+                let handle = tokio::spawn(async move {
+                    for (index, item_id) in item_ids.iter().enumerate().skip(start_index) {
+                        let first = index == start_index;
+                        let media_source_id = if first { media_source_id.clone() } else { None };
+                        let start_ticks = if first { start_ticks } else { None };
+
+                        if let Err(e) = play_item(
+                            &host,
+                            &api_key,
+                            &user_id,
+                            item_id,
+                            media_source_id,
+                            start_ticks,
+                        )
+                        .await
+                        {
+                            log!("播放 {} 失败: {}", item_id, e);
+                        }
+
+                        if flag.load(Ordering::SeqCst) {
+                            break;
+                        }
+                    }
+                });
+
+                current = Some(Playback { handle, cancelled });
+            }
+            Command::Playstate {
+                command,
+                seek_ticks,
+            } => match command.as_str() {
+                "Stop" => stop(&mut current).await,
+                _ => playstate(&command, seek_ticks),
+            },
+            Command::General { name, arguments } => general(&name, &arguments),
+        }
+    }
+
+    Ok(())
+}
+
+// 结束当前播放队列，等待 Stop 回传完成，超时后放弃
+async fn stop(current: &mut Option<Playback>) {
+    if let Some(mut playback) = current.take() {
+        playback.cancelled.store(true, Ordering::SeqCst);
+        let _ = property::send_command(json!(["quit"]));
+        if tokio::time::timeout(shutdown::TIMEOUT, &mut playback.handle)
+            .await
+            .is_err()
+        {
+            log!("等待播放结束超时");
+            playback.handle.abort();
+        }
+    }
+}
+
+async fn play_item(
+    host: &str,
+    api_key: &str,
+    user_id: &str,
+    item_id: &str,
+    media_source_id: Option<String>,
+    start_ticks: Option<Ticks>,
+) -> Result<()> {
+    let media = player::item_media(host, api_key, user_id, item_id, media_source_id).await?;
+    player::play(media, start_ticks, None, Options::default()).await
+}
+
+fn playstate(command: &str, seek_ticks: Option<Ticks>) {
+    let ipc_command = match command {
+        "Pause" => json!(["set_property", "pause", true]),
+        "Unpause" => json!(["set_property", "pause", false]),
+        "PlayPause" => json!(["cycle", "pause"]),
+        "Seek" => json!([
+            "seek",
+            seek_ticks.unwrap_or_default().as_secs_f64(),
+            "absolute"
+        ]),
+        "Rewind" => json!(["seek", -10, "relative"]),
+        "FastForward" => json!(["seek", 30, "relative"]),
+        // 结束当前条目，队列继续播放下一项
+        "NextTrack" => json!(["quit"]),
+        _ => {
+            log!("不支持的播放控制命令: {}", command);
+            return;
+        }
+    };
+
+    if let Err(e) = property::send_command(ipc_command) {
+        log!("发送播放控制命令失败: {}", e);
+    }
+}
+
+fn general(name: &str, arguments: &Value) {
+    let ipc_command = match name {
+        "DisplayMessage" => json!(["show-text", arguments["Text"].as_str().unwrap_or(""), 5000]),
+        "SetVolume" => json!([
+            "set_property",
+            "volume",
+            arguments["Volume"]
+                .as_str()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(85)
+        ]),
+        "Mute" => json!(["set_property", "mute", true]),
+        "Unmute" => json!(["set_property", "mute", false]),
+        "ToggleMute" => json!(["cycle", "mute"]),
+        _ => {
+            log!("不支持的通用命令: {}", name);
+            return;
+        }
+    };
+
+    if let Err(e) = property::send_command(ipc_command) {
+        log!("发送通用命令失败: {}", e);
+    }
+}
+
+// 保持与服务器的连接，断开后自动重连
+async fn listen(server: Server, tx: UnboundedSender<Command>) {
+    loop {
+        if let Err(e) = session(&server, &tx).await {
+            log!("服务器 {} 连接断开: {}", server.name, e);
+        }
+
+        if tx.is_closed() {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    }
+}
+
+async fn session(server: &Server, tx: &UnboundedSender<Command>) -> Result<()> {
+    let host = server.host();
+    let api_key = &auth::token(server).await?;
+
+    let user_id = request::get_user_id(host, api_key).await?.user_id;
+    let headers = request::construct_headers(api_key, &user_id).await?;
+    request::report_capabilities(host, headers).await?;
+
+    let ws_url = format!(
+        "{}/embywebsocket?api_key={}&deviceId={}",
+        host.replacen("http", "ws", 1),
+        api_key,
+        device_id()?
+    );
+    let (ws, _) = connect_async(ws_url).await?;
+    let (mut sink, mut stream) = ws.split();
+
+    log!("已连接到服务器: {}", server.name);
+
+    let keep_alive = Message::text(json!({ "MessageType": "KeepAlive" }).to_string());
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => sink.send(keep_alive.clone()).await?,
+            message = stream.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Err(anyhow!("Connection closed")),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                };
+
+                let json: Value = serde_json::from_str(text.as_str())?;
+                let data = &json["Data"];
+
+                let command = match json["MessageType"].as_str() {
+                    Some("ForceKeepAlive") => {
+                        let secs = data.as_u64().unwrap_or(60).max(2) / 2;
+                        interval = tokio::time::interval(Duration::from_secs(secs));
+                        continue;
+                    }
+                    Some("Play") => {
+                        if data["PlayCommand"].as_str().is_some_and(|c| c != "PlayNow") {
+                            log!("不支持的播放命令: {}", data["PlayCommand"]);
+                            continue;
+                        }
+
+                        Command::Play {
+                            host: host.to_string(),
+                            api_key: api_key.to_string(),
+                            user_id: user_id.clone(),
+                            item_ids: data["ItemIds"]
+                                .as_array()
+                                .map(|ids| {
+                                    ids.iter()
+                                        .filter_map(|id| id.as_str().map(str::to_string))
+                                        .collect()
+                                })
+                                .unwrap_or_default(),
+                            start_index: data["StartIndex"].as_u64().unwrap_or(0) as usize,
+                            start_ticks: data["StartPositionTicks"].as_u64().map(Ticks),
+                            media_source_id: data["MediaSourceId"].as_str().map(str::to_string),
+                        }
+                    }
+                    Some("Playstate") => Command::Playstate {
+                        command: data["Command"].as_str().unwrap_or_default().to_string(),
+                        seek_ticks: data["SeekPositionTicks"].as_u64().map(Ticks),
+                    },
+                    Some("GeneralCommand") => Command::General {
+                        name: data["Name"].as_str().unwrap_or_default().to_string(),
+                        arguments: data["Arguments"].clone(),
+                    },
+                    _ => continue,
+                };
+
+                tx.send(command)?;
+            }
+        }
+    }
+}
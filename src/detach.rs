@@ -0,0 +1,108 @@
+// 脱离浏览器运行，浏览器退出或结束进程组后 mpv 和进度回传不受影响
+use crate::config::{runtime_dir, state_dir, Config};
+use crate::log;
+use anyhow::{Context, Result};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// 重新启动的进程带有这个环境变量，避免再次脱离
+const DETACHED_ENV: &str = "MPV_HANDLER_DETACHED";
+const PID_FILE: &str = "mpv-handler.pid";
+
+// DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP
+#[cfg(windows)]
+const DETACH_FLAGS: u32 = 0x0000_0008 | 0x0000_0200;
+
+// 配置了 detach 时以相同参数在新会话中重新启动自己
+// 返回 None 表示已经交给新进程，当前进程应直接退出
+pub fn detach() -> Result<Option<Pidfile>> {
+    if std::env::var_os(DETACHED_ENV).is_some() {
+        return Ok(Some(Pidfile::create()));
+    }
+    if !Config::load().is_ok_and(|config| config.detach) {
+        return Ok(Some(Pidfile::none()));
+    }
+
+    respawn().context("Failed to detach from the browser")?;
+    Ok(None)
+}
+
+// tokio 已经启动了多个线程，fork 之后不安全，所以重新执行自己代替 double fork
+fn respawn() -> Result<()> {
+    // 脱离后没有终端，日志写入状态目录
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_dir()?.join("detached.log"))?;
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DETACHED_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+
+    #[cfg(unix)]
+    unsafe {
+        // 新会话没有控制终端，也不属于浏览器的进程组
+        command.pre_exec(|| match libc::setsid() {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    #[cfg(windows)]
+    command.creation_flags(DETACH_FLAGS);
+
+    // 不等待子进程，它在当前进程退出后继续运行
+    let child = command.spawn()?;
+    log!("已在后台启动，进程 ID: {}", child.id());
+
+    Ok(())
+}
+
+// 运行时目录中的 pidfile，进程结束时删除
+pub struct Pidfile {
+    path: Option<PathBuf>,
+}
+
+impl Pidfile {
+    pub fn none() -> Pidfile {
+        Pidfile { path: None }
+    }
+
+    // 写入失败时只记录日志，不影响播放
+    fn create() -> Pidfile {
+        let result = runtime_dir().and_then(|dir| {
+            let path = dir.join(PID_FILE);
+            std::fs::write(&path, std::process::id().to_string())
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            Ok(path)
+        });
+        match result {
+            Ok(path) => Pidfile { path: Some(path) },
+            Err(e) => {
+                log!("写入 pidfile 失败: {:#}", e);
+                Pidfile { path: None }
+            }
+        }
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        // 之后启动的实例可能已经覆盖了这个文件
+        let pid = std::process::id().to_string();
+        if std::fs::read_to_string(path).is_ok_and(|text| text.trim() == pid) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+// 诊断启动失败的原因，逐项输出检查结果
+use crate::auth;
+use crate::config::{Config, MPVClient};
+use crate::log;
+use crate::network::extractor::{self, M4};
+use crate::network::property::{self, ipc_server};
+use crate::network::request::{construct_headers, get_media_sources, get_public_info, get_user_id};
+use crate::player;
+use anyhow::{anyhow, Context, Result};
+use handler_common::mpv;
+use serde_json::json;
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use url::Url;
+
+#[derive(Default)]
+struct Checklist {
+    failed: usize,
+}
+
+impl Checklist {
+    // 输出一项检查结果，成功时返回结果
+    fn check<T>(&mut self, name: &str, result: Result<(T, String)>) -> Option<T> {
+        match result {
+            Ok((value, detail)) => {
+                log!("[OK]   {}: {}", name, detail);
+                Some(value)
+            }
+            Err(e) => {
+                log!("[FAIL] {}: {:#}", name, e);
+                self.failed += 1;
+                None
+            }
+        }
+    }
+
+    fn skip(&self, name: &str, reason: &str) {
+        log!("[SKIP] {}: {}", name, reason);
+    }
+}
+
+pub async fn run(mpv_url: Option<&str>) -> Result<()> {
+    let mut checklist = Checklist::default();
+
+    let Some(config) = checklist.check(
+        "config",
+        Config::load().map(|config| (config, "loaded".to_string())),
+    ) else {
+        return Err(anyhow!("1 check failed"));
+    };
+
+    checklist.check("mpv", check_mpv(&config).map(|version| ((), version)));
+    checklist.check("mpv IPC", check_ipc().map(|detail| ((), detail)));
+
+    match config.proxy.as_deref().filter(|proxy| !proxy.is_empty()) {
+        Some(proxy) => {
+            checklist.check(
+                "proxy",
+                check_proxy(proxy).await.map(|()| ((), proxy.to_string())),
+            );
+        }
+        None => checklist.skip("proxy", "not set"),
+    }
+
+    match mpv_url {
+        Some(mpv_url) => check_url(&mut checklist, mpv_url).await,
+        None => {
+            for server in &config.servers {
+                let name = format!("server {}", server.name);
+                checklist.check(
+                    &name,
+                    get_public_info(server.host()).await.map(|info| ((), info)),
+                );
+            }
+        }
+    }
+
+    match checklist.failed {
+        0 => Ok(()),
+        1 => Err(anyhow!("1 check failed")),
+        n => Err(anyhow!("{} checks failed", n)),
+    }
+}
+
+// 检查 mpv 路径和版本，失败时列出找到的 mpv
+fn check_mpv(config: &Config) -> Result<String> {
+    let command_line = MPVClient::command_line(config);
+    if let Some(version) = mpv::version(&command_line) {
+        return Ok(format!("{} ({})", version, command_line));
+    }
+
+    let found = mpv::candidates();
+    match found.is_empty() {
+        true => Err(anyhow!("Failed to run {}, no mpv found", command_line)),
+        false => Err(anyhow!(
+            "Failed to run {}, found: {}",
+            command_line,
+            found.join(", ")
+        )),
+    }
+}
+
+// 后台启动 mpv，确认 IPC 可以收发命令
+fn check_ipc() -> Result<String> {
+    // 已有 mpv 在运行时直接向它查询
+    if property::wait_ready(Duration::ZERO).is_ok() {
+        property::send_command(json!(["get_property", "mpv-version"]))?;
+        return Ok(format!("{} (used by a running mpv)", ipc_server()));
+    }
+
+    let mut command = MPVClient::build()?;
+    command
+        .args(["--idle=yes", "--no-terminal", "--force-window=no"])
+        .arg(format!("--input-ipc-server={}", ipc_server()))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    #[cfg(windows)]
+    command.creation_flags(134_217_728);
+
+    let mut child = command.spawn().context("Failed to start mpv")?;
+
+    let result = property::wait_ready(Duration::from_secs(10))
+        .and_then(|()| property::send_command(json!(["get_property", "mpv-version"])));
+
+    if result.is_err() || property::send_command(json!(["quit"])).is_err() {
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+
+    result.map(|_| match MPVClient::sandbox() {
+        Some(sandbox) => format!("{} ({:?})", ipc_server(), sandbox),
+        None => ipc_server().to_string(),
+    })
+}
+
+// 检查代理地址能否连接
+async fn check_proxy(proxy: &str) -> Result<()> {
+    let url = Url::parse(proxy).context("Invalid proxy url")?;
+    let host = url.host_str().ok_or(anyhow!("Proxy host not found"))?;
+    let port = url.port_or_known_default().unwrap_or(1080);
+
+    tokio::time::timeout(Duration::from_secs(5), TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| anyhow!("Connection to {}:{} timed out", host, port))?
+        .with_context(|| format!("Failed to connect to {}:{}", host, port))?;
+
+    Ok(())
+}
+
+// 按播放流程解析链接并查询用户和媒体信息
+async fn check_url(checklist: &mut Checklist, mpv_url: &str) {
+    if extractor::is_item_link(mpv_url) {
+        return check_item(checklist, mpv_url).await;
+    }
+
+    let Some(video_url) = checklist.check(
+        "parse url",
+        extractor::extract_urls(mpv_url).map(|(video_url, subfile_url)| {
+            let detail = match subfile_url.is_empty() {
+                true => "no subtitle".to_string(),
+                false => "with subtitle".to_string(),
+            };
+            (video_url, detail)
+        }),
+    ) else {
+        return;
+    };
+
+    let Some(M4 {
+        host,
+        item_id,
+        media_source_id,
+        api_key,
+    }) = checklist.check(
+        "parse params",
+        extractor::extract_params(&video_url).map(|params| {
+            let detail = format!("{} item {}", params.host, params.item_id);
+            (params, detail)
+        }),
+    )
+    else {
+        return;
+    };
+
+    checklist.check(
+        "server",
+        get_public_info(&host).await.map(|info| ((), info)),
+    );
+
+    let token = match api_key {
+        Some(api_key) => Ok((api_key, "from url".to_string())),
+        None => auth::lookup(&host)
+            .await
+            .map(|api_key| (api_key, "from config or secret store".to_string())),
+    };
+    let Some(api_key) = checklist.check("token", token) else {
+        return;
+    };
+
+    let user = get_user_id(&host, &api_key).await.and_then(|id| {
+        match id.user_id.is_empty() || id.user_id == "null" {
+            true => Err(anyhow!("No session found for this token")),
+            false => Ok((id.user_id.clone(), id.user_id)),
+        }
+    });
+    let Some(user_id) = checklist.check("user", user) else {
+        return;
+    };
+
+    let item = async {
+        let headers = construct_headers(&api_key, &user_id).await?;
+        let sources = get_media_sources(&host, &item_id, &user_id, headers).await?;
+        match sources.iter().any(|source| source.id == media_source_id) {
+            true => Ok(((), format!("{} media source(s)", sources.len()))),
+            false => Err(anyhow!("Media source {} not found", media_source_id)),
+        }
+    };
+    checklist.check("item", item.await);
+}
+
+// 检查 mpv://item 链接对应的服务器、登录和条目
+async fn check_item(checklist: &mut Checklist, mpv_url: &str) {
+    let Some((name, item_id)) = checklist.check(
+        "parse url",
+        extractor::extract_item(mpv_url).map(|(name, item_id)| {
+            let detail = format!("server {} item {}", name, item_id);
+            ((name, item_id), detail)
+        }),
+    ) else {
+        return;
+    };
+
+    let Ok(config) = Config::load() else {
+        return;
+    };
+    let Some(server) = checklist.check(
+        "server",
+        config
+            .server(&name)
+            .ok_or_else(|| anyhow!("Server {} not found in config", name))
+            .map(|server| (server, server.host().to_string())),
+    ) else {
+        return;
+    };
+    let host = server.host();
+
+    let Some(api_key) = checklist.check(
+        "token",
+        auth::token(server)
+            .await
+            .map(|api_key| (api_key, "logged in".to_string())),
+    ) else {
+        return;
+    };
+
+    let user = get_user_id(host, &api_key)
+        .await
+        .map(|id| (id.user_id.clone(), id.user_id));
+    let Some(user_id) = checklist.check("user", user) else {
+        return;
+    };
+
+    checklist.check(
+        "item",
+        player::item_media(host, &api_key, &user_id, &item_id, None)
+            .await
+            .map(|media| ((), format!("media source {}", media.media_source_id))),
+    );
+}
@@ -0,0 +1,190 @@
+// 普通链接直接交给 mpv 播放，不回传进度，只允许配置中的协议和主机
+use crate::cli::{Failure, Options};
+use crate::config::{Config, GenericPolicy};
+use crate::log;
+use crate::network::property;
+use crate::player;
+use crate::shutdown::Watch;
+use crate::trust;
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::time::Duration;
+use url::Url;
+
+// 没有主机的协议，不检查 hosts
+const HOSTLESS_SCHEMES: &[&str] = &["magnet"];
+
+// 检查链接是否允许作为普通链接播放
+pub fn check(url: &str) -> Result<()> {
+    allowed(policy(&Config::load()?)?, url)
+}
+
+// 没有配置 [generic] 时不允许播放普通链接
+pub fn policy(config: &Config) -> Result<&GenericPolicy> {
+    config.generic.as_ref().ok_or_else(|| {
+        anyhow!("Not an Emby stream url, add a [generic] section to the config to play other urls")
+    })
+}
+
+pub fn allowed(policy: &GenericPolicy, url: &str) -> Result<()> {
+    trust::check_url(url, &policy.schemes)?;
+    let (scheme, rest) = url.split_once(':').unwrap_or_default();
+    let scheme = scheme.to_ascii_lowercase();
+
+    // magnet 等没有主机的协议只检查协议
+    if HOSTLESS_SCHEMES.contains(&scheme.as_str()) {
+        return Ok(());
+    }
+
+    // ytdl:// 后面是交给 yt-dlp 的链接，检查其中的主机
+    let target = match scheme.as_str() {
+        "ytdl" => ytdl_target(rest)?,
+        _ => url.to_string(),
+    };
+    let host = Url::parse(&target)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        .filter(|host| !host.is_empty())
+        .ok_or_else(|| anyhow!("Url without a host rejected: {}", url))?;
+
+    let allowed = policy.hosts.iter().any(|allowed| {
+        let allowed = allowed.trim_start_matches("*.").to_ascii_lowercase();
+        host == allowed || host.ends_with(&format!(".{}", allowed))
+    });
+    match allowed {
+        true => Ok(()),
+        false => Err(anyhow!("Host {} is not allowed", host)),
+    }
+}
+
+// ytdl://example.com/watch 和 ytdl://https://example.com/watch 都交给 yt-dlp
+// 只接受 http(s) 链接，ytsearch: 等没有主机的写法不能按主机限制
+fn ytdl_target(rest: &str) -> Result<String> {
+    let rest = rest.trim_start_matches('/');
+    match rest.split_once("://") {
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("http") => Ok(rest.to_string()),
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("https") => Ok(rest.to_string()),
+        Some((scheme, _)) => Err(anyhow!("Scheme {} is not allowed in ytdl urls", scheme)),
+        None if rest.contains(':') && !rest.contains('/') => {
+            Err(anyhow!("Ytdl url without a host rejected: {}", rest))
+        }
+        None => Ok(format!("https://{}", rest)),
+    }
+}
+
+// 用配置的参数启动 mpv 播放，mpv 退出后返回
+pub async fn play(video_url: String, subfile_url: String, options: Options) -> Result<()> {
+    let policy = Config::load()?.generic.unwrap_or_default();
+
+    let mut mpv = player::mpv_command(options)?;
+    mpv.args(&policy.args);
+
+    // 字幕只对这个文件生效
+    let mut loadfile = json!({
+        "name": "loadfile",
+        "url": video_url,
+        "flags": "replace",
+        "options": {},
+    });
+    if !subfile_url.is_empty() {
+        allowed(&policy, &subfile_url).context(Failure::Parse)?;
+        loadfile["options"]["sub-files-append"] = json!(subfile_url);
+    }
+
+    if options.dry_run || options.verbose {
+        log!("{}", player::command_line(&mpv));
+        log!("IPC {}", loadfile);
+    }
+    if options.dry_run {
+        return Ok(());
+    }
+
+    // 启动 mpv 之前开始监听退出信号，等待 mpv 启动时被终止也能清理
+    let mut watch = Watch::new();
+    let mut child = mpv
+        .spawn()
+        .context("Failed to start mpv")
+        .context(Failure::Player)?;
+
+    // 等待 mpv 启动时会阻塞，不占用异步运行时的线程
+    let load = tokio::task::spawn_blocking(move || -> Result<()> {
+        property::wait_ready(Duration::from_secs(10))?;
+        property::send_command(loadfile)?;
+        Ok(())
+    });
+    match watch.guard(async { load.await? }).await {
+        Ok(()) => {}
+        Err(e) if watch.interrupted => {
+            watch.finish(&mut child).await;
+            return Err(e);
+        }
+        Err(e) => {
+            let _ = child.kill();
+            return Err(e.context(Failure::Player));
+        }
+    }
+
+    while watch.running(&mut child).await {}
+    watch.finish(&mut child).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::allowed;
+    use crate::config::GenericPolicy;
+
+    fn policy(schemes: &[&str], hosts: &[&str]) -> GenericPolicy {
+        GenericPolicy {
+            schemes: schemes.iter().map(|s| s.to_string()).collect(),
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            args: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn allows_listed_hosts_and_subdomains() {
+        let policy = policy(&["https"], &["example.com", "*.video.org"]);
+
+        assert!(allowed(&policy, "https://example.com/a.mp4").is_ok());
+        assert!(allowed(&policy, "https://cdn.EXAMPLE.com/a.mp4").is_ok());
+        assert!(allowed(&policy, "https://a.video.org/a.mp4").is_ok());
+        assert!(allowed(&policy, "https://video.org/a.mp4").is_ok());
+    }
+
+    #[test]
+    fn rejects_other_hosts_and_schemes() {
+        let policy = policy(&["https"], &["example.com"]);
+
+        assert!(allowed(&policy, "https://notexample.com/a.mp4").is_err());
+        assert!(allowed(&policy, "https://example.com.evil.org/a.mp4").is_err());
+        assert!(allowed(&policy, "http://example.com/a.mp4").is_err());
+        assert!(allowed(&policy, "file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn hostless_schemes_only_check_the_scheme() {
+        let policy = policy(&["magnet", "https"], &[]);
+
+        assert!(allowed(&policy, "magnet:?xt=urn:btih:abc").is_ok());
+        assert!(allowed(&policy, "https://example.com/a.mp4").is_err());
+    }
+
+    #[test]
+    fn hosted_schemes_check_the_host() {
+        let policy = policy(&["rtsp", "ytdl"], &["example.com"]);
+
+        assert!(allowed(&policy, "rtsp://cam.example.com/live").is_ok());
+        assert!(allowed(&policy, "rtsp://evil.host/live").is_err());
+        assert!(allowed(&policy, "rtsp:live").is_err());
+
+        assert!(allowed(&policy, "ytdl://example.com/watch?v=1").is_ok());
+        assert!(allowed(&policy, "ytdl://https://www.example.com/watch?v=1").is_ok());
+        assert!(allowed(&policy, "ytdl://evil.host/watch").is_err());
+        assert!(allowed(&policy, "ytdl://https://evil.host/watch").is_err());
+        assert!(allowed(&policy, "ytdl://http://example.com@evil.host/").is_err());
+        assert!(allowed(&policy, "ytdl://ytsearch:cats").is_err());
+        assert!(allowed(&policy, "ytdl://file:///etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,457 @@
+// 本地观看记录，每次播放一行 JSON，保存在状态目录的 history.jsonl 中
+use crate::auth;
+use crate::config::{state_dir, Config};
+use crate::log;
+use crate::network::request::{
+    construct_headers, get_user_id, playing_status, PlayMethod, PlaySession, PlayStatus,
+};
+use crate::ticks::Ticks;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const REPORT_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub host: String,
+    pub item_id: String,
+    pub media_source_id: String,
+    pub title: String,
+    pub start_ticks: Ticks,
+    pub stop_ticks: Ticks,
+    // 条目时长，mpv 没有读到时为 None
+    pub duration_ticks: Option<Ticks>,
+    // Unix 秒
+    pub started_at: u64,
+    pub stopped_at: u64,
+    // 结束进度还没有回传到服务器
+    #[serde(default)]
+    pub pending: bool,
+    #[serde(default)]
+    pub offline: bool,
+}
+
+#[derive(Args)]
+pub struct Filter {
+    /// Only sessions on this server, a name or url from the config or part of a host
+    #[arg(long)]
+    server: Option<String>,
+
+    /// Only sessions of this item
+    #[arg(long)]
+    item: Option<String>,
+
+    /// Only titles containing this text
+    search: Option<String>,
+
+    /// Only sessions whose progress has not reached the server yet
+    #[arg(long)]
+    pending: bool,
+
+    /// Show at most this many sessions, newest first
+    #[arg(short = 'n', long, default_value_t = 20)]
+    limit: usize,
+}
+
+// 追加一次播放记录，同时更新条目的播放位置
+pub fn record(session: &Session) -> Result<()> {
+    save_position(&session.host, &session.item_id, session.stop_ticks);
+
+    let _lock = lock()?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path()?)?;
+    writeln!(file, "{}", serde_json::to_string(session)?).context("Failed to save history")
+}
+
+// 读取全部记录，跳过损坏的行
+pub fn load() -> Result<Vec<Session>> {
+    load_from(&history_path()?)
+}
+
+fn load_from(path: &Path) -> Result<Vec<Session>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// 先写临时文件再替换，避免中途退出时丢失记录
+// 调用者需要持有文件锁，否则会覆盖其他进程同时追加的记录
+fn save(sessions: &[Session]) -> Result<()> {
+    save_to(&history_path()?, sessions)
+}
+
+fn save_to(path: &Path, sessions: &[Session]) -> Result<()> {
+    let tmp = path.with_extension("jsonl.tmp");
+
+    let mut text = String::new();
+    for session in sessions {
+        text.push_str(&serde_json::to_string(session)?);
+        text.push('\n');
+    }
+    std::fs::write(&tmp, text)?;
+    std::fs::rename(&tmp, path).context("Failed to save history")
+}
+
+// 本地记录的播放位置，播放期间每次更新进度时写入
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Position {
+    pub ticks: Ticks,
+    // Unix 秒
+    pub updated_at: u64,
+}
+
+// 条目在本地最近的播放位置，没有单独保存时使用最近一次观看记录
+pub fn position(host: &str, item_id: &str) -> Option<Position> {
+    let key = position_key(host, item_id);
+    if let Some(position) = load_positions()
+        .ok()
+        .and_then(|positions| positions.get(&key).copied())
+    {
+        return Some(position);
+    }
+
+    load()
+        .ok()?
+        .into_iter()
+        .filter(|session| session.host == host && session.item_id == item_id)
+        .max_by_key(|session| session.stopped_at)
+        .map(|session| Position {
+            ticks: session.stop_ticks,
+            updated_at: session.stopped_at,
+        })
+}
+
+// 保存播放位置，失败时只记录日志，不影响播放
+// 和观看记录共用文件锁，避免多个进程同时改写时丢失其他条目的位置
+pub fn save_position(host: &str, item_id: &str, ticks: Ticks) {
+    let result = lock().and_then(|_lock| {
+        let path = positions_path()?;
+        let mut positions = load_positions_from(&path)?;
+        positions.insert(
+            position_key(host, item_id),
+            Position {
+                ticks,
+                updated_at: now(),
+            },
+        );
+        save_positions_to(&path, &positions)
+    });
+
+    if let Err(e) = result {
+        log!("保存播放位置失败: {}", e);
+    }
+}
+
+fn load_positions() -> Result<HashMap<String, Position>> {
+    load_positions_from(&positions_path()?)
+}
+
+fn load_positions_from(path: &Path) -> Result<HashMap<String, Position>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).context("Failed to parse positions"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// 和观看记录一样先写临时文件再替换，调用者需要持有文件锁
+fn save_positions_to(path: &Path, positions: &HashMap<String, Position>) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string(positions)?)?;
+    std::fs::rename(&tmp, path).context("Failed to save positions")
+}
+
+fn position_key(host: &str, item_id: &str) -> String {
+    format!("{} {}", host, item_id)
+}
+
+// 追加和改写观看记录、保存播放位置时持有的文件锁，进程退出时自动释放
+fn lock() -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(state_dir()?.join("history.lock"))?;
+    file.lock().context("Failed to lock history")?;
+    Ok(file)
+}
+
+fn positions_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("positions.json"))
+}
+
+// 重新回传没有送达的进度，每个条目只回传最近一次
+// 每个条目的回传有超时，服务器无法访问时不会一直等待
+pub async fn replay() {
+    let sessions = match load() {
+        Ok(sessions) if sessions.iter().any(|session| session.pending) => sessions,
+        Ok(_) => return,
+        Err(e) => {
+            log!("读取观看记录失败: {}", e);
+            return;
+        }
+    };
+
+    let mut seen = HashSet::new();
+    let mut sent = Vec::new();
+    for session in sessions.iter().rev() {
+        if !session.pending || !seen.insert((&session.host, &session.item_id)) {
+            continue;
+        }
+
+        match tokio::time::timeout(REPORT_TIMEOUT, report(session)).await {
+            Ok(Ok(())) => sent.push(session),
+            Ok(Err(e)) => log!("回传 {} 的进度失败: {}", session.item_id, e),
+            Err(_) => log!("回传 {} 的进度超时", session.item_id),
+        }
+    }
+    if sent.is_empty() {
+        return;
+    }
+
+    log!("已回传 {} 条未送达的播放进度", sent.len());
+    let result = lock().and_then(|_lock| {
+        // 回传期间其他进程可能追加了记录，重新读取后再标记
+        let mut sessions = load()?;
+        for session in sessions.iter_mut() {
+            // 更早的记录已经被这次的进度覆盖
+            session.pending &= !sent.iter().any(|sent| {
+                session.host == sent.host
+                    && session.item_id == sent.item_id
+                    && session.stopped_at <= sent.stopped_at
+            });
+        }
+        save(&sessions)
+    });
+    if let Err(e) = result {
+        log!("保存观看记录失败: {}", e);
+    }
+}
+
+async fn report(session: &Session) -> Result<()> {
+    let api_key = auth::lookup(&session.host).await?;
+    let user = get_user_id(&session.host, &api_key).await?;
+    let headers = construct_headers(&api_key, &user.user_id).await?;
+    let play_session = PlaySession {
+        id: user.play_session_id,
+        method: match session.offline {
+            true => PlayMethod::DirectPlay,
+            false => PlayMethod::DirectStream,
+        },
+    };
+
+    playing_status(
+        session.stop_ticks,
+        &session.host,
+        &session.item_id,
+        &play_session,
+        &session.media_source_id,
+        PlayStatus::Stop,
+        headers,
+    )
+    .await
+}
+
+// 按条件列出观看记录，最新的在前
+pub fn list(filter: &Filter) -> Result<()> {
+    let server = filter.server.as_ref().map(|server| {
+        Config::load()
+            .ok()
+            .and_then(|config| config.server(server).map(|s| s.host().to_string()))
+            .unwrap_or_else(|| server.clone())
+    });
+    let search = filter.search.as_ref().map(|search| search.to_lowercase());
+
+    let sessions: Vec<Session> = load()?
+        .into_iter()
+        .rev()
+        .filter(|s| server.as_ref().is_none_or(|server| s.host.contains(server)))
+        .filter(|s| filter.item.as_ref().is_none_or(|item| &s.item_id == item))
+        .filter(|s| {
+            search
+                .as_ref()
+                .is_none_or(|search| s.title.to_lowercase().contains(search))
+        })
+        .filter(|s| !filter.pending || s.pending)
+        .take(filter.limit)
+        .collect();
+
+    if sessions.is_empty() {
+        log!("没有观看记录");
+    }
+
+    for session in sessions {
+        let duration = session
+            .duration_ticks
+            .map(|ticks| format!(" / {}", ticks))
+            .unwrap_or_default();
+        let mut flags = String::new();
+        if session.offline {
+            flags.push_str("  (离线)");
+        }
+        if session.pending {
+            flags.push_str("  (未回传)");
+        }
+
+        log!(
+            "{}  {}  [{} {}]  {} -> {}{}{}",
+            format_time(session.started_at),
+            session.title,
+            session.host,
+            session.item_id,
+            session.start_ticks,
+            session.stop_ticks,
+            duration,
+            flags
+        );
+    }
+
+    Ok(())
+}
+
+fn history_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("history.jsonl"))
+}
+
+fn format_time(secs: u64) -> String {
+    DateTime::from_timestamp(secs as i64, 0)
+        .map(|time| {
+            time.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_from, load_positions_from, save_positions_to, save_to, Position, Session};
+    use crate::ticks::Ticks;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn session(item_id: &str, pending: bool) -> Session {
+        Session {
+            host: "https://emby.example.com".to_string(),
+            item_id: item_id.to_string(),
+            media_source_id: "ms1".to_string(),
+            title: "Film".to_string(),
+            start_ticks: Ticks::ZERO,
+            stop_ticks: Ticks(600_000_000),
+            duration_ticks: None,
+            started_at: 1_700_000_000,
+            stopped_at: 1_700_000_060,
+            pending,
+            offline: false,
+        }
+    }
+
+    // 每个测试使用单独的目录，结束时删除
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "mpv-handler-test-{}-{}",
+                std::process::id(),
+                test
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = TempDir::new("round_trip");
+        let path = dir.path("history.jsonl");
+        save_to(&path, &[session("1", true), session("2", false)]).unwrap();
+
+        let sessions = load_from(&path).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].item_id, "1");
+        assert!(sessions[0].pending);
+        assert_eq!(sessions[1].stop_ticks, Ticks(600_000_000));
+        assert!(!path.with_extension("jsonl.tmp").exists());
+    }
+
+    #[test]
+    fn load_skips_broken_lines() {
+        let dir = TempDir::new("broken");
+        let path = dir.path("history.jsonl");
+        let line = serde_json::to_string(&session("1", false)).unwrap();
+        std::fs::write(&path, format!("{}\n{{\"host\": \n\n{}\n", line, line)).unwrap();
+
+        assert_eq!(load_from(&path).unwrap().len(), 2);
+        assert!(load_from(&dir.path("missing.jsonl")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn load_accepts_records_without_flags() {
+        let dir = TempDir::new("old");
+        let path = dir.path("history.jsonl");
+        std::fs::write(
+            &path,
+            r#"{"host":"h","item_id":"1","media_source_id":"m","title":"t","start_ticks":0,"stop_ticks":5,"duration_ticks":null,"started_at":1,"stopped_at":2}"#,
+        )
+        .unwrap();
+
+        let sessions = load_from(&path).unwrap();
+        assert!(!sessions[0].pending && !sessions[0].offline);
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        let dir = TempDir::new("positions");
+        let path = dir.path("positions.json");
+        assert!(load_positions_from(&path).unwrap().is_empty());
+
+        let mut positions = HashMap::new();
+        positions.insert(
+            "h 1".to_string(),
+            Position {
+                ticks: Ticks(600_000_000),
+                updated_at: 1_700_000_000,
+            },
+        );
+        save_positions_to(&path, &positions).unwrap();
+
+        let positions = load_positions_from(&path).unwrap();
+        assert_eq!(positions["h 1"].ticks, Ticks(600_000_000));
+        assert!(!path.with_extension("json.tmp").exists());
+
+        // 损坏的文件报错，不会被当成空文件覆盖
+        std::fs::write(&path, "{\"h 1\": ").unwrap();
+        assert!(load_positions_from(&path).is_err());
+    }
+}
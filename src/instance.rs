@@ -0,0 +1,156 @@
+// 单实例模式，后启动的进程通过控制 socket 把请求交给正在运行的实例
+use crate::cli::Options;
+#[cfg(unix)]
+use crate::config::runtime_dir;
+use crate::config::Config;
+use crate::log;
+#[cfg(unix)]
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+#[cfg(unix)]
+const SOCKET: &str = "mpv-handler.sock";
+#[cfg(unix)]
+const LOCK: &str = "mpv-handler.lock";
+#[cfg(windows)]
+const PIPE: &str = r"\\.\pipe\mpv-handler";
+
+// 转交的请求，由正在运行的实例重新解析
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    // 浏览器打开的 mpv:// 链接
+    Link(String),
+    Stream {
+        url: String,
+        subfile: String,
+        server: Option<String>,
+    },
+    Item {
+        server: String,
+        item_id: String,
+    },
+}
+
+fn enabled(options: Options) -> bool {
+    !options.dry_run && Config::load().is_ok_and(|config| config.single_instance.is_some())
+}
+
+// 有正在运行的实例时把请求交给它，返回 true 表示当前进程可以直接退出
+pub async fn forward(request: &Request, options: Options) -> bool {
+    if !enabled(options) {
+        return false;
+    }
+
+    let result = async {
+        let mut stream = connect().await?;
+        let line = serde_json::to_string(request)? + "\n";
+        stream.write_all(line.as_bytes()).await?;
+        stream.shutdown().await?;
+        anyhow::Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => {
+            log!("已交给正在运行的 mpv-handler");
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+// 成为接收请求的实例，监听失败时只记录日志，照常播放
+pub fn serve(options: Options) -> Option<UnboundedReceiver<Request>> {
+    if !enabled(options) {
+        return None;
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    match listen(tx) {
+        Ok(()) => Some(rx),
+        Err(e) => {
+            log!("监听控制 socket 失败，不接收其他链接: {}", e);
+            None
+        }
+    }
+}
+
+// 每个连接发送一行 JSON
+async fn receive(stream: impl AsyncRead + Unpin, tx: UnboundedSender<Request>) {
+    let mut line = String::new();
+    if BufReader::new(stream).read_line(&mut line).await.is_err() {
+        return;
+    }
+
+    match serde_json::from_str(&line) {
+        Ok(request) => {
+            let _ = tx.send(request);
+        }
+        Err(e) => log!("无效的转交请求: {}", e),
+    }
+}
+
+#[cfg(unix)]
+async fn connect() -> Result<tokio::net::UnixStream> {
+    Ok(tokio::net::UnixStream::connect(runtime_dir()?.join(SOCKET)).await?)
+}
+
+#[cfg(unix)]
+fn listen(tx: UnboundedSender<Request>) -> Result<()> {
+    // 同时启动的进程只有拿到锁的一个监听，锁在进程退出时释放
+    let dir = runtime_dir()?;
+    let lock = std::fs::File::create(dir.join(LOCK))?;
+    lock.try_lock()
+        .map_err(|_| anyhow!("Another instance is starting"))?;
+
+    // 连接被拒绝说明 socket 是异常退出的进程留下的，这时才删除
+    let path = dir.join(SOCKET);
+    match std::os::unix::net::UnixStream::connect(&path) {
+        Ok(_) => return Err(anyhow!("Another instance is listening")),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(&path)?;
+        }
+        Err(_) => {}
+    }
+    let listener = tokio::net::UnixListener::bind(&path)?;
+
+    tokio::spawn(async move {
+        let _lock = lock;
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(receive(stream, tx.clone()));
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn connect() -> Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    Ok(tokio::net::windows::named_pipe::ClientOptions::new().open(PIPE)?)
+}
+
+#[cfg(windows)]
+fn listen(tx: UnboundedSender<Request>) -> Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    // first_pipe_instance 保证只有一个实例能创建管道
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(PIPE)?;
+
+    tokio::spawn(async move {
+        while server.connect().await.is_ok() {
+            let client = server;
+            server = match ServerOptions::new().create(PIPE) {
+                Ok(server) => server,
+                Err(_) => break,
+            };
+            tokio::spawn(receive(client, tx.clone()));
+        }
+    });
+
+    Ok(())
+}
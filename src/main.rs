@@ -3,26 +3,41 @@
     windows_subsystem = "windows"
 )]
 
+mod auth;
+mod cli;
 mod config;
+mod daemon;
+mod detach;
+mod doctor;
+mod generic;
+mod history;
+mod instance;
+mod logging;
 mod network;
-
-use crate::network::{extractor, property, request};
+mod offline;
+mod osd;
+mod player;
+mod register;
+mod resume;
+mod secret;
+mod shutdown;
+mod source;
+mod ticks;
+mod trust;
+
+use crate::network::extractor;
 use anyhow::{anyhow, Context, Result};
-use config::MPVClient;
+use clap::Parser;
+use cli::{Cli, Command, Failure, Options};
+use config::Config;
 use extractor::M4;
-use network::request::{construct_headers, get_proxy, get_ua, get_user_id, playing_status};
-use std::env;
-#[cfg(windows)]
-use std::os::windows::process::CommandExt;
-use std::process::Child;
-use std::result::Result::Ok;
+use instance::Request;
+use network::request::get_user_id;
+use player::Media;
+use std::process::ExitCode;
 use std::sync::OnceLock;
-use std::time::{Duration, Instant};
 use tokio::runtime::{self, Runtime};
-
-fn deviceid_gen() -> String {
-    uuid::Uuid::new_v4().to_string()
-}
+use tokio::sync::mpsc;
 
 pub fn runtime() -> &'static Runtime {
     static RUNTIME: OnceLock<Runtime> = OnceLock::new();
@@ -36,184 +51,368 @@ pub fn runtime() -> &'static Runtime {
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    env::set_var("DEVICE_ID", deviceid_gen());
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    logging::set_verbose(cli.options.verbose);
 
-    let args: Vec<String> = std::env::args().collect();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // 返回的错误信息中可能带有 token
+            eprintln!("Error: {}", logging::redact(&format!("{:#}", e)));
+            cli::exit_code(&e)
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let options = cli.options;
 
-    if args.len() != 2 {
-        return Err(anyhow!("Usage: {} <mpv://play/...>", args[0]));
+    match cli.command {
+        // 从标准输入读取 token 并保存
+        Some(Command::SetToken { server_url }) => {
+            let mut token = String::new();
+            std::io::stdin().read_line(&mut token)?;
+            auth::store_token(&server_url, token.trim())
+        }
+        // 注册或移除 mpv:// 协议
+        Some(Command::Register(mut register)) => {
+            register.dry_run = options.dry_run;
+            register::register(&register)
+        }
+        Some(Command::Unregister(mut register)) => {
+            register.dry_run = options.dry_run;
+            register::unregister(&register)
+        }
+        // 下载条目用于离线播放
+        Some(Command::Download { url, item, server }) => match (item, server) {
+            (Some(item_id), Some(server)) => download_item(&server, &item_id, options).await,
+            (_, server) => download(url.unwrap_or_default(), server, options).await,
+        },
+        // 列出或播放已下载的条目
+        Some(Command::Offline { item }) => match item {
+            Some(item_id) => offline::play(&item_id, options).await,
+            None => {
+                // 列出之前先等待回传结束
+                if !options.dry_run && !options.no_report {
+                    history::replay().await;
+                }
+                offline::list()
+            }
+        },
+        // 列出观看记录
+        Some(Command::History(filter)) => history::list(&filter),
+        // 作为可投屏的播放端常驻运行
+        Some(Command::Daemon) => daemon::run().await,
+        // 检查配置、mpv 和服务器连接
+        Some(Command::Doctor { url }) => doctor::run(url.as_deref()).await,
+        // 直接播放推流链接或条目
+        Some(Command::Play {
+            url,
+            item,
+            subfile,
+            server,
+        }) => {
+            let request = match (item, server) {
+                (Some(item_id), Some(server)) => Request::Item { server, item_id },
+                (_, server) => {
+                    let url = url.unwrap_or_default();
+                    let subfile = subfile.unwrap_or_default();
+                    if is_generic(&url)? {
+                        return generic::play(url, subfile, options).await;
+                    }
+                    Request::Stream {
+                        url,
+                        subfile,
+                        server,
+                    }
+                }
+            };
+            if instance::forward(&request, options).await {
+                return Ok(());
+            }
+            play(request, options).await
+        }
+        None => {
+            let mpv_url = cli
+                .url
+                .ok_or(anyhow!("Missing mpv:// link"))
+                .context(Failure::Parse)?;
+
+            // 先检查链接，无效的链接不转交也不脱离浏览器
+            let generic = match extractor::is_item_link(&mpv_url) {
+                true => {
+                    extractor::extract_item(&mpv_url).context(Failure::Parse)?;
+                    None
+                }
+                false => {
+                    let (video_url, subfile_url) =
+                        extractor::extract_urls(&mpv_url).context(Failure::Parse)?;
+                    let generic = is_generic(&video_url)?;
+                    check_links(&video_url, &subfile_url, generic)?;
+                    generic.then_some((video_url, subfile_url))
+                }
+            };
+
+            // 普通链接没有回传会话，不转交给正在运行的实例
+            let request = Request::Link(mpv_url);
+            if generic.is_none() && instance::forward(&request, options).await {
+                return Ok(());
+            }
+            let Some(_pidfile) = detach(options)? else {
+                return Ok(());
+            };
+            match generic {
+                Some((video_url, subfile_url)) => {
+                    generic::play(video_url, subfile_url, options).await
+                }
+                None => play(request, options).await,
+            }
+        }
     }
+}
 
-    let mpv_url = &args[1];
+// 单实例模式下同时接收其他进程转交的请求，交给播放器替换或加入播放列表
+async fn play(request: Request, options: Options) -> Result<()> {
+    let requests = instance::serve(options);
+    let media = resolve(request, options).await?;
+
+    let queue = requests.map(|mut requests| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                match resolve(request, options).await {
+                    Ok(media) => {
+                        if tx.send(media).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => log!("处理转交的请求失败: {:#}", e),
+                }
+            }
+        });
+        rx
+    });
 
-    // 匹配视频连接和外置字幕链接
-    let (video_url, subfile_url) = match extractor::extract_urls(mpv_url) {
-        Ok(urls) => urls,
-        Err(e) => {
-            return Err(anyhow!("Error: {}", e));
+    player::play(media, None, queue, options).await
+}
+
+// 解析链接或参数，生成要播放的条目
+async fn resolve(request: Request, options: Options) -> Result<Media> {
+    match request {
+        Request::Link(mpv_url) if extractor::is_item_link(&mpv_url) => {
+            let (server, item_id) = extractor::extract_item(&mpv_url).context(Failure::Parse)?;
+            item(&server, &item_id, options).await
         }
-    };
+        Request::Link(mpv_url) => {
+            // 匹配视频连接和外置字幕链接
+            let (video_url, subfile_url) =
+                extractor::extract_urls(&mpv_url).context(Failure::Parse)?;
+            stream(video_url, subfile_url, None, options).await
+        }
+        Request::Stream {
+            url,
+            subfile,
+            server,
+        } => stream(url, subfile, server, options).await,
+        Request::Item { server, item_id } => item(&server, &item_id, options).await,
+    }
+}
 
+// 获取 token 后生成要播放的条目，server 为配置中的服务器名称或地址
+async fn stream(
+    video_url: String,
+    subfile_url: String,
+    server: Option<String>,
+    options: Options,
+) -> Result<Media> {
     // 匹配视频链接中的参数
     let M4 {
         host,
         item_id,
         media_source_id,
         api_key,
-    } = extractor::extract_params(&video_url)?;
-
-    // 开启ipc-server
-    #[cfg(windows)]
-    let ipc_server = "--input-ipc-server=\\\\.\\pipe\\mpvsocket";
-    #[cfg(unix)]
-    let ipc_server = "--input-ipc-server=/tmp/mpvsocket";
-
-    // 指定日志输出等级
-    let msg_level = "--msg-level=all=error";
-
-    // 强制立即打开播放器窗口
-    let force_window = "--force-window=immediate";
-    // set volume to 75%
-    let vol_arg = "--volume=85";
-
-    // 设置mpv请求的UA
-    let ua_arg = format!("--user-agent={}", get_ua()?);
-
-    // 设置proxy
-    let proxy_arg = format!("--http-proxy={}", get_proxy()?);
-
-    // 设置请求头
-    let user_id = get_user_id(&host, &api_key).await?;
-    let headers = construct_headers(&api_key, &user_id.user_id).await?;
-
-    // 获取重定向之后的推流链接
-    // let video_url = get_redirect(
-    //     format!(
-    //         "{}&PlaySessionId={}",
-    //         video_url,
-    //         user_id.unwrap().play_session_id
-    //     ),
-    //     headers.clone(),
-    // )
-    // .await;
-
-    // 显示媒体标题信息
-    let chapter_info = request::get_chapter_info(&host, &item_id, headers.clone()).await?;
-
-    // 获取视频播放进度
-    let start_ticks =
-        request::get_start_position(&host, &api_key, &item_id, headers.clone()).await?;
-
-    let start_arg = format!("--start={}", start_ticks / 10_000_000_u64);
-    let title_arg = format!("--force-media-title={}", chapter_info);
-
-    let mut mpv = MPVClient::build()?;
-
-    if !subfile_url.is_empty() {
-        let sub_arg = format!("--sub-file={}", subfile_url);
-        mpv.arg(video_url)
-            .arg(sub_arg)
-            .arg(ua_arg)
-            .arg(vol_arg)
-            .arg(ipc_server)
-            .arg(msg_level)
-            .arg(force_window)
-            .arg(title_arg)
-            .arg(start_arg)
-            .arg(proxy_arg);
-        #[cfg(windows)]
-        mpv.creation_flags(134_217_728u32);
-    } else {
-        mpv.arg(video_url)
-            .arg(ua_arg)
-            .arg(vol_arg)
-            .arg(ipc_server)
-            .arg(msg_level)
-            .arg(force_window)
-            .arg(title_arg)
-            .arg(start_arg)
-            .arg(proxy_arg);
-        #[cfg(windows)]
-        mpv.creation_flags(134_217_728u32);
-    }
+    } = extractor::extract_params(&video_url).context(Failure::Parse)?;
 
-    // 启动子进程
-    let mut child: Child = match mpv.spawn() {
-        Ok(child) => child,
-        Err(e) => {
-            return Err(anyhow!("Error: {}", e));
-        }
+    check_links(&video_url, &subfile_url, false)?;
+    trust::check_server(&host, options).await?;
+
+    remember_token(&host, api_key.as_deref(), options);
+    let api_key = token(&host, api_key, server).await?;
+
+    // token 只通过请求头传给 mpv
+    let video_url = extractor::strip_api_key(&video_url, &api_key).context(Failure::Parse)?;
+    let subfile_url = match subfile_url.is_empty() {
+        true => subfile_url,
+        false => extractor::strip_api_key(&subfile_url, &api_key).context(Failure::Parse)?,
     };
 
-    // 检测进程退出状态
-    fn is_process_running(child: &mut Child) -> bool {
-        std::thread::sleep(Duration::from_secs(2));
+    flush_reports(options);
 
-        match child.try_wait() {
-            Ok(None) => true,
-            Ok(Some(_)) => false,
-            Err(_) => false,
+    let mut media = Media {
+        video_url,
+        subfile_url,
+        host,
+        item_id,
+        media_source_id,
+        api_key,
+        versions: Vec::new(),
+    };
+
+    // 配置了版本选择条件时，不再使用链接中的版本
+    // 只开启 picker 时以链接中的版本作为默认选项
+    let policy = Config::load()?.media_source;
+    if policy.is_set() || policy.picker {
+        let user_id = get_user_id(&media.host, &media.api_key).await?.user_id;
+        let media_source_id = (!policy.is_set()).then(|| media.media_source_id.clone());
+        let chosen = player::item_media(
+            &media.host,
+            &media.api_key,
+            &user_id,
+            &media.item_id,
+            media_source_id,
+        )
+        .await?;
+
+        if chosen.media_source_id != media.media_source_id {
+            log!("使用版本 {} 代替链接中的版本", chosen.media_source_id);
+            media.video_url = chosen.video_url;
+            media.media_source_id = chosen.media_source_id;
         }
+        media.versions = chosen.versions;
+    }
+
+    Ok(media)
+}
+
+// 按配置中的服务器和条目 ID 播放，推流链接由 PlaybackInfo 生成
+async fn item(server: &str, item_id: &str, options: Options) -> Result<Media> {
+    let config = Config::load()?;
+    let server = config
+        .server(server)
+        .ok_or_else(|| anyhow!("Server {} not found in config", server))
+        .context(Failure::Auth)?;
+
+    let host = server.host();
+    let api_key = auth::token(server).await.context(Failure::Auth)?;
+    let user_id = get_user_id(host, &api_key).await?.user_id;
+
+    flush_reports(options);
+
+    player::item_media(host, &api_key, &user_id, item_id, None).await
+}
+
+// 下载 mpv:// 链接或推流链接对应的条目
+async fn download(url: String, server: Option<String>, options: Options) -> Result<()> {
+    if extractor::is_item_link(&url) {
+        let (server, item_id) = extractor::extract_item(&url).context(Failure::Parse)?;
+        return download_item(&server, &item_id, options).await;
     }
 
-    let mut ticks = start_ticks;
+    let (video_url, subfile_url) = match url.starts_with("mpv://") {
+        true => extractor::extract_urls(&url).context(Failure::Parse)?,
+        false => (url, String::new()),
+    };
+    let M4 {
+        host,
+        item_id,
+        media_source_id,
+        api_key,
+    } = extractor::extract_params(&video_url).context(Failure::Parse)?;
+
+    check_links(&video_url, &subfile_url, false)?;
+    trust::check_server(&host, options).await?;
 
-    // 标记播放开始
-    let _ = request::playing_status(
-        ticks,
+    // 离线播放的进度之后也要用这个 token 回传
+    remember_token(&host, api_key.as_deref(), options);
+    let api_key = token(&host, api_key, server).await?;
+    let subfile_url = match subfile_url.is_empty() {
+        true => subfile_url,
+        false => extractor::strip_api_key(&subfile_url, &api_key).context(Failure::Parse)?,
+    };
+
+    flush_reports(options);
+    offline::download(
         &host,
-        &item_id,
         &api_key,
+        &item_id,
         &media_source_id,
-        request::PlayStatus::Play,
-        headers.clone(),
+        &subfile_url,
+        options,
     )
-    .await;
-
-    let mut last_print = Instant::now();
-
-    // 上传播放进度
-    while is_process_running(&mut child) {
-        if last_print.elapsed() >= Duration::from_secs(10) {
-            #[cfg(windows)]
-            let time_pos = property::get_time_pos_win();
-            #[cfg(unix)]
-            let time_pos = property::get_time_pos_unix();
-
-            if let Ok(duration) = time_pos {
-                ticks = duration.parse::<f64>().context("Failed to parse ticks")? as u64
-                    * 10_000_000_u64;
-                // 更新进度
-                let _ = request::playing_status(
-                    ticks,
-                    &host,
-                    &item_id,
-                    &api_key,
-                    &media_source_id,
-                    request::PlayStatus::Progress,
-                    headers.clone(),
-                )
-                .await;
-
-                last_print = Instant::now();
-            } else {
-                println!("更新播放时间失败")
-            }
+    .await
+}
+
+// 按配置中的服务器和条目 ID 下载，版本按配置的策略选择
+async fn download_item(server: &str, item_id: &str, options: Options) -> Result<()> {
+    let config = Config::load()?;
+    let server = config
+        .server(server)
+        .ok_or_else(|| anyhow!("Server {} not found in config", server))
+        .context(Failure::Auth)?;
+
+    let host = server.host();
+    let api_key = auth::token(server).await.context(Failure::Auth)?;
+    let user_id = get_user_id(host, &api_key).await?.user_id;
+
+    flush_reports(options);
+
+    let media = player::item_media(host, &api_key, &user_id, item_id, None).await?;
+    offline::download(host, &api_key, item_id, &media.media_source_id, "", options).await
+}
+
+// 链接中没有 api_key 时，从配置或密钥存储中查找 token
+async fn token(host: &str, api_key: Option<String>, server: Option<String>) -> Result<String> {
+    match (api_key, server) {
+        (Some(api_key), _) => Ok(api_key),
+        (None, Some(server)) => {
+            let config = Config::load()?;
+            let server = config
+                .server(&server)
+                .ok_or_else(|| anyhow!("Server {} not found in config", server))
+                .context(Failure::Auth)?;
+            auth::token(server).await.context(Failure::Auth)
         }
+        (None, None) => auth::lookup(host).await.context(Failure::Auth),
     }
+}
 
-    // 标记播放结束
-    let _ = playing_status(
-        ticks,
-        &host,
-        &item_id,
-        &api_key,
-        &media_source_id,
-        request::PlayStatus::Stop,
-        headers,
-    )
-    .await;
+// 链接中带有 api_key 时保存下来，没有送达的进度之后可以重新回传
+fn remember_token(host: &str, api_key: Option<&str>, options: Options) {
+    if let Some(api_key) = api_key.filter(|_| !options.dry_run) {
+        auth::remember(host, api_key);
+    }
+}
 
-    Ok(())
+// 不是 Emby 推流链接时，检查是否允许作为普通链接播放
+fn is_generic(video_url: &str) -> Result<bool> {
+    match extractor::extract_params(video_url) {
+        Ok(_) => Ok(false),
+        Err(e) => {
+            generic::check(video_url)
+                .map_err(|reason| e.context(reason.to_string()))
+                .context(Failure::Parse)?;
+            Ok(true)
+        }
+    }
+}
+
+// 检查视频和字幕链接，普通链接按 [generic] 的规则
+fn check_links(video_url: &str, subfile_url: &str, generic: bool) -> Result<()> {
+    trust::check_links(video_url, subfile_url, generic).context(Failure::Parse)
+}
+
+// 链接有效后再脱离浏览器，无效的链接仍然在浏览器启动的进程中报错
+fn detach(options: Options) -> Result<Option<detach::Pidfile>> {
+    match options.dry_run {
+        true => Ok(Some(detach::Pidfile::none())),
+        false => detach::detach(),
+    }
+}
+
+// 服务器可访问时顺带在后台回传之前没有送达的进度，不耽误播放
+fn flush_reports(options: Options) {
+    if !options.dry_run && !options.no_report {
+        tokio::spawn(history::replay());
+    }
 }
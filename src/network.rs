@@ -1,9 +1,12 @@
 pub mod extractor {
     use anyhow::{anyhow, Context, Result};
     use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use percent_encoding::percent_decode_str;
     use regex::Regex;
     use url::Url;
 
+    const ITEM_PREFIX: &str = "mpv://item/";
+
     pub fn extract_urls(mpv_url: &str) -> Result<(String, String)> {
         let url = mpv_url
             .trim_end_matches('=')
@@ -38,21 +41,50 @@ pub mod extractor {
         }
     }
 
+    // 解析 mpv://item/<服务器名称>/<条目 ID>
+    pub fn extract_item(mpv_url: &str) -> Result<(String, String)> {
+        let path = mpv_url
+            .strip_prefix(ITEM_PREFIX)
+            .ok_or(anyhow!("Invalid URL scheme"))?;
+
+        let (server, item_id) = path.trim_end_matches('/').split_once('/').ok_or(anyhow!(
+            "Invalid item link, expected mpv://item/<server>/<item-id>"
+        ))?;
+
+        let server = percent_decode_str(server)
+            .decode_utf8()
+            .context("Invalid server name")?;
+        if server.is_empty() {
+            return Err(anyhow!("Server name not found"));
+        }
+
+        // Emby 的 ID 为数字，Jellyfin 为 GUID
+        if item_id.is_empty()
+            || !item_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(anyhow!("Invalid item id: {}", item_id));
+        }
+
+        Ok((server.into_owned(), item_id.to_string()))
+    }
+
+    pub fn is_item_link(mpv_url: &str) -> bool {
+        mpv_url.starts_with(ITEM_PREFIX)
+    }
+
     pub struct M4 {
         pub host: String,
         pub item_id: String,
         pub media_source_id: String,
-        pub api_key: String,
+        pub api_key: Option<String>,
     }
 
     pub fn extract_params(video_url: &str) -> Result<M4> {
         let url = Url::parse(video_url).context("Invalid streaming url")?;
 
-        let host = format!(
-            "{}://{}",
-            url.scheme(),
-            url.host_str().ok_or(anyhow!("Hostname not found"))?
-        );
+        let host = host_of(&url)?;
 
         // 提取 MediaSourceId
         let Some(media_source_id) = url
@@ -63,14 +95,11 @@ pub mod extractor {
             return Err(anyhow!("Failed to extract MediaSourceId"));
         };
 
-        // 提取 api_key
-        let Some(api_key) = url
+        // 提取 api_key，链接中没有时需要登录获取
+        let api_key = url
             .query_pairs()
             .find(|(key, _)| key == "api_key")
-            .map(|(_, value)| value)
-        else {
-            return Err(anyhow!("Failed to extract api_key"));
-        };
+            .map(|(_, value)| value.to_string());
 
         let pattern = Regex::new(r"^.*/videos/(\d+)/.*")?;
 
@@ -85,56 +114,81 @@ pub mod extractor {
             host,
             item_id,
             media_source_id: media_source_id.to_string(),
-            api_key: api_key.to_string(),
+            api_key,
         })
     }
+
+    // 从链接中移除 api_key，token 改由请求头传递
+    pub fn strip_api_key(video_url: &str, api_key: &str) -> Result<String> {
+        let mut url = Url::parse(video_url).context("Invalid streaming url")?;
+
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(key, value)| !(key == "api_key" && value == api_key))
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+
+        if pairs.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+
+        Ok(url.to_string())
+    }
+
+    // 链接是否和 host（scheme://host[:port]）在同一服务器
+    pub fn same_host(url: &str, host: &str) -> bool {
+        Url::parse(url).is_ok_and(|url| host_of(&url).is_ok_and(|h| h == host))
+    }
+
+    // 提取 scheme://host[:port]，默认端口不带端口号
+    pub fn host_of(url: &Url) -> Result<String> {
+        let host = url.host_str().ok_or(anyhow!("Hostname not found"))?;
+        match url.port() {
+            Some(port) => Ok(format!("{}://{}:{}", url.scheme(), host, port)),
+            None => Ok(format!("{}://{}", url.scheme(), host)),
+        }
+    }
 }
 
 pub mod request {
 
     use super::request;
-    use crate::config::{Config, DEFAULT_UA};
+    use crate::cli::Failure;
+    use crate::config::{device_id, device_name, Config, CLIENT_NAME, CLIENT_VERSION, DEFAULT_UA};
+    use crate::log;
+    use crate::ticks::Ticks;
+    use crate::verbose;
     use anyhow::{anyhow, Context, Result};
-    use reqwest::header::{HeaderMap, HeaderValue};
-    use reqwest::Client;
+    use reqwest::header::{HeaderMap, HeaderValue, RANGE};
+    use reqwest::{Client, StatusCode};
+    use serde::Deserialize;
     use serde_json::{json, Value};
-    use std::env;
+    use std::path::Path;
     use std::sync::OnceLock;
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncWriteExt;
+
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
     // 构造请求标头
     pub async fn construct_headers(api_key: &str, user_id: &str) -> Result<HeaderMap> {
         let mut headers = HeaderMap::new();
 
         headers.insert("X-Emby-Token", HeaderValue::from_str(api_key)?);
+        headers.insert("X-Emby-Device-Id", HeaderValue::from_str(device_id()?)?);
+        headers.insert("X-Emby-Device-Name", HeaderValue::from_str(&device_name())?);
+        headers.insert("X-Emby-Client", HeaderValue::from_static(CLIENT_NAME));
         headers.insert(
-            "X-Emby-Device-Id",
-            HeaderValue::from_str(&env::var("DEVICE_ID")?.to_string())?,
+            "X-Emby-Client-Version",
+            HeaderValue::from_static(CLIENT_VERSION),
         );
-        headers.insert(
-            "X-Emby-Device-Name",
-            HeaderValue::from_str(&get_device_name())?,
-        );
-        headers.insert("X-Emby-Client", HeaderValue::from_static("Emby"));
         headers.insert("X-Emby-User-Id", HeaderValue::from_str(user_id)?);
 
         Ok(headers)
     }
 
-    fn get_device_name() -> String {
-        // 尝试在类 Unix 系统上获取主机名
-        #[cfg(unix)]
-        {
-            env::var("HOSTNAME").unwrap_or_else(|_| "Unknown".to_string())
-        }
-
-        // 尝试在 Windows 系统上获取主机名
-        // 如果是 Windows 系统，使用 "COMPUTERNAME" 环境变量
-        #[cfg(windows)]
-        {
-            env::var("COMPUTERNAME").unwrap_or_else(|_| "Unknown".to_string())
-        }
-    }
-
     // 获取UA，默认为ExoPlayer
     pub fn get_ua() -> Result<String> {
         match Config::load().context("Failed to load config")?.useragent {
@@ -162,17 +216,21 @@ pub mod request {
         CLIENT.get_or_init(|| request::build().expect("Failed to build Client"))
     }
 
+    // 服务器无法访问时连接很快超时，下载可能很久，不限制整个请求的时间
     fn build() -> Result<reqwest::Client> {
         let proxy = get_proxy()?;
         let ua = get_ua()?;
+        let builder = Client::builder()
+            .user_agent(ua)
+            .connect_timeout(CONNECT_TIMEOUT);
 
         if proxy.is_empty() {
-            Ok(Client::builder().user_agent(ua).build()?)
+            Ok(builder.build()?)
         } else {
-            println!("正在使用代理访问: {}", proxy);
+            log!("正在使用代理访问: {}", proxy);
             let req_proxy = reqwest::Proxy::all(proxy).context("Failed to set proxy")?;
 
-            Ok(Client::builder().proxy(req_proxy).user_agent(ua).build()?)
+            Ok(builder.proxy(req_proxy).build()?)
         }
     }
 
@@ -236,17 +294,36 @@ pub mod request {
         }
     }
 
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub enum PlayMethod {
+        DirectPlay,
+        DirectStream,
+        Transcode,
+    }
+
+    // 回传进度时使用的播放会话
+    #[derive(Clone)]
+    pub struct PlaySession {
+        pub id: String,
+        pub method: PlayMethod,
+    }
+
     pub async fn playing_status(
-        ticks: u64,
+        ticks: Ticks,
         host: &str,
         item_id: &str,
-        api_key: &str,
+        session: &PlaySession,
         media_source_id: &str,
         status: PlayStatus,
         headers: HeaderMap,
     ) -> Result<()> {
         let params = [("reqformat", "json")];
-        let body = json!({"IsMuted":false,"IsPaused":false,"RepeatMode":"RepeatNone","SubtitleOffset":0,"PlaybackRate":1,"MaxStreamingBitrate":1_000_000_000_u64,"BufferedRanges":[],"PlayMethod":"DirectStream","PlaySessionId":&get_user_id(host, api_key).await?.play_session_id,"MediaSourceId":media_source_id,"CanSeek":true,"ItemId":item_id,"PositionTicks":ticks});
+        let play_method = match session.method {
+            PlayMethod::DirectPlay => "DirectPlay",
+            PlayMethod::DirectStream => "DirectStream",
+            PlayMethod::Transcode => "Transcode",
+        };
+        let body = json!({"IsMuted":false,"IsPaused":false,"RepeatMode":"RepeatNone","SubtitleOffset":0,"PlaybackRate":1,"MaxStreamingBitrate":1_000_000_000_u64,"BufferedRanges":[],"PlayMethod":play_method,"PlaySessionId":&session.id,"MediaSourceId":media_source_id,"CanSeek":true,"ItemId":item_id,"PositionTicks":ticks.0});
 
         let url = match status {
             PlayStatus::Play => format!("{}/emby/Sessions/Playing", host),
@@ -254,6 +331,7 @@ pub mod request {
             PlayStatus::Stop => format!("{}/emby/Sessions/Playing/Stopped", host),
         };
 
+        verbose!("POST {} PositionTicks={}", url, ticks);
         let res = client()
             .post(url)
             .headers(headers)
@@ -264,11 +342,17 @@ pub mod request {
 
         match res {
             Ok(res) => {
-                println!("{}，服务状态: {}", status, res.status());
+                log!("{}，服务状态: {}", status, res.status());
+                if !res.status().is_success() {
+                    return Err(anyhow!("Request failed, {}", res.status()));
+                }
+                Ok(())
+            }
+            Err(e) => {
+                log!("{}出错", status);
+                Err(e.into())
             }
-            Err(_) => println!("{}出错", status),
         }
-        Ok(())
     }
 
     pub async fn get_chapter_info(host: &str, item_id: &str, headers: HeaderMap) -> Result<String> {
@@ -298,6 +382,449 @@ pub mod request {
         }
     }
 
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct MediaSource {
+        pub id: String,
+        pub container: Option<String>,
+        pub name: Option<String>,
+        pub bitrate: Option<u64>,
+        #[serde(default)]
+        pub media_streams: Vec<MediaStream>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct MediaStream {
+        #[serde(rename = "Type")]
+        pub kind: String,
+        pub codec: Option<String>,
+        pub height: Option<u32>,
+        pub index: Option<u32>,
+        pub language: Option<String>,
+        #[serde(default)]
+        pub is_external: bool,
+    }
+
+    impl MediaSource {
+        pub fn video(&self) -> Option<&MediaStream> {
+            self.media_streams
+                .iter()
+                .find(|stream| stream.kind == "Video")
+        }
+    }
+
+    // 获取条目的全部媒体源
+    pub async fn get_media_sources(
+        host: &str,
+        item_id: &str,
+        user_id: &str,
+        headers: HeaderMap,
+    ) -> Result<Vec<MediaSource>> {
+        let url = format!("{}/emby/Items/{}/PlaybackInfo", host, item_id);
+
+        let response = client()
+            .get(url)
+            .headers(headers)
+            .query(&[("UserId", user_id)])
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Request failed, {}", response.status()));
+        }
+
+        let json: Value = response.json().await?;
+        let sources: Vec<MediaSource> = serde_json::from_value(json["MediaSources"].clone())
+            .context("Failed to parse MediaSources")?;
+
+        Ok(sources)
+    }
+
+    // 构造直链推流地址，token 通过请求头传给 mpv
+    pub fn stream_url(host: &str, item_id: &str, source: &MediaSource) -> String {
+        let container = source
+            .container
+            .as_deref()
+            .map(|c| format!(".{}", c))
+            .unwrap_or_default();
+
+        format!(
+            "{}/emby/videos/{}/stream{}?Static=true&MediaSourceId={}",
+            host, item_id, container, source.id
+        )
+    }
+
+    // 外挂字幕的下载地址
+    pub fn subtitle_url(
+        host: &str,
+        item_id: &str,
+        source_id: &str,
+        stream: &MediaStream,
+    ) -> String {
+        let format = match stream.codec.as_deref() {
+            Some("subrip") | None => "srt",
+            Some(codec) => codec,
+        };
+
+        format!(
+            "{}/emby/Videos/{}/{}/Subtitles/{}/Stream.{}",
+            host,
+            item_id,
+            source_id,
+            stream.index.unwrap_or(0),
+            format
+        )
+    }
+
+    // 获取条目的章节，返回 (名称, 开始位置)
+    pub async fn get_chapters(
+        host: &str,
+        user_id: &str,
+        item_id: &str,
+        headers: HeaderMap,
+    ) -> Result<Vec<(String, Ticks)>> {
+        let url = format!("{}/emby/Users/{}/Items/{}", host, user_id, item_id);
+
+        let response = client().get(url).headers(headers).send().await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Request failed, {}", response.status()));
+        }
+
+        let json: Value = response.json().await?;
+        let chapters = json["Chapters"]
+            .as_array()
+            .map(|chapters| {
+                chapters
+                    .iter()
+                    .map(|chapter| {
+                        (
+                            chapter["Name"].as_str().unwrap_or_default().to_string(),
+                            Ticks(chapter["StartPositionTicks"].as_u64().unwrap_or(0)),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(chapters)
+    }
+
+    // 下载到 path，文件已存在时用 Range 请求续传
+    pub async fn download(url: &str, headers: HeaderMap, path: &Path) -> Result<()> {
+        let offset = tokio::fs::metadata(path)
+            .await
+            .map(|meta| meta.len())
+            .unwrap_or(0);
+
+        let mut request = client().get(url).headers(headers);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let mut response = request.send().await?;
+
+        // 已经下载完整
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(());
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!("Request failed, {}", response.status()));
+        }
+
+        // 服务器不支持 Range 时返回 200，从头下载
+        let resume = response.status() == StatusCode::PARTIAL_CONTENT;
+        let (mut file, mut done) = match resume {
+            true => {
+                log!("从 {} 字节处继续下载", offset);
+                let file = tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .await?;
+                (file, offset)
+            }
+            false => (tokio::fs::File::create(path).await?, 0),
+        };
+        let total = response.content_length().map(|length| length + done);
+
+        let mut last_print = Instant::now();
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            done += chunk.len() as u64;
+
+            if last_print.elapsed() >= Duration::from_secs(5) {
+                match total {
+                    Some(total) => log!("已下载 {:.1}%", done as f64 * 100.0 / total as f64),
+                    None => log!("已下载 {:.1} MB", done as f64 / 1_000_000.0),
+                }
+                last_print = Instant::now();
+            }
+        }
+        file.flush().await?;
+
+        if total.is_some_and(|total| done < total) {
+            return Err(anyhow!("Download interrupted at {} bytes", done));
+        }
+
+        Ok(())
+    }
+
+    // 请求服务器转码为 HLS，返回 m3u8 链接和转码的播放会话
+    pub async fn get_transcode(
+        host: &str,
+        item_id: &str,
+        user_id: &str,
+        media_source_id: &str,
+        max_bitrate: u64,
+        headers: HeaderMap,
+    ) -> Result<(String, PlaySession)> {
+        let url = format!("{}/emby/Items/{}/PlaybackInfo", host, item_id);
+        let max_bitrate_param = max_bitrate.to_string();
+        let params = [
+            ("UserId", user_id),
+            ("MediaSourceId", media_source_id),
+            ("MaxStreamingBitrate", &max_bitrate_param),
+            ("EnableTranscoding", "true"),
+            ("EnableDirectPlay", "false"),
+            ("EnableDirectStream", "false"),
+            ("AutoOpenLiveStream", "false"),
+        ];
+        // 只声明 HLS 转码能力，服务器据此生成 TranscodingUrl
+        let body = json!({
+            "DeviceProfile": {
+                "MaxStreamingBitrate": max_bitrate,
+                "DirectPlayProfiles": [],
+                "TranscodingProfiles": [{
+                    "Container": "ts",
+                    "Type": "Video",
+                    "VideoCodec": "h264",
+                    "AudioCodec": "aac,mp3",
+                    "Context": "Streaming",
+                    "Protocol": "hls",
+                    "MaxAudioChannels": "6",
+                    "BreakOnNonKeyFrames": true,
+                }],
+                "SubtitleProfiles": [
+                    {"Format": "srt", "Method": "External"},
+                    {"Format": "ass", "Method": "External"},
+                ],
+            }
+        });
+
+        let response = client()
+            .post(url)
+            .headers(headers)
+            .query(&params)
+            .json(&body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Request failed, {}", response.status()));
+        }
+
+        let json: Value = response.json().await?;
+        let sources = json["MediaSources"].as_array().cloned().unwrap_or_default();
+        let path = sources
+            .iter()
+            .find(|source| source["Id"] == media_source_id)
+            .or(sources.first())
+            .and_then(|source| source["TranscodingUrl"].as_str())
+            .ok_or(anyhow!("Server did not return a transcoding url"))?;
+
+        let url = match path.starts_with("/emby") {
+            true => format!("{}{}", host, path),
+            false => format!("{}/emby{}", host, path),
+        };
+        let session = PlaySession {
+            id: json["PlaySessionId"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            method: PlayMethod::Transcode,
+        };
+
+        Ok((url, session))
+    }
+
+    // 结束服务器上的转码任务
+    pub async fn stop_transcode(
+        host: &str,
+        play_session_id: &str,
+        headers: HeaderMap,
+    ) -> Result<()> {
+        let url = format!("{}/emby/Videos/ActiveEncodings", host);
+        let params = [
+            ("DeviceId", device_id()?),
+            ("PlaySessionId", play_session_id),
+        ];
+
+        let response = client()
+            .delete(url)
+            .headers(headers)
+            .query(&params)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Request failed, {}", response.status()));
+        }
+
+        Ok(())
+    }
+
+    // 注册为可远程控制的播放端
+    pub async fn report_capabilities(host: &str, headers: HeaderMap) -> Result<()> {
+        let url = format!("{}/emby/Sessions/Capabilities/Full", host);
+        let body = json!({
+            "PlayableMediaTypes": ["Video", "Audio"],
+            "SupportedCommands": ["Play", "Playstate", "DisplayMessage", "SetVolume", "Mute", "Unmute", "ToggleMute"],
+            "SupportsMediaControl": true,
+        });
+
+        let response = client()
+            .post(url)
+            .headers(headers)
+            .json(&body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Request failed, {}", response.status()));
+        }
+
+        Ok(())
+    }
+
+    // 登录时使用的客户端认证标头
+    fn authorization() -> Result<String> {
+        Ok(format!(
+            r#"MediaBrowser Client="{}", Device="{}", DeviceId="{}", Version="{}""#,
+            CLIENT_NAME,
+            device_name(),
+            device_id()?,
+            CLIENT_VERSION
+        ))
+    }
+
+    // 从登录结果中提取 AccessToken
+    fn parse_auth(json: &Value) -> Result<String> {
+        json["AccessToken"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or(anyhow!("AccessToken not found"))
+    }
+
+    // 使用用户名和密码登录
+    pub async fn authenticate_by_name(
+        host: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<String> {
+        let url = format!("{}/emby/Users/AuthenticateByName", host);
+        let body = json!({ "Username": username, "Pw": password });
+
+        let response = client()
+            .post(url)
+            .header("X-Emby-Authorization", authorization()?)
+            .json(&body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Authentication failed, {}", response.status()));
+        }
+
+        parse_auth(&response.json().await?)
+    }
+
+    // 发起 Quick Connect，返回 (Secret, Code)
+    pub async fn quick_connect_initiate(host: &str) -> Result<(String, String)> {
+        let url = format!("{}/QuickConnect/Initiate", host);
+
+        let response = client()
+            .post(url)
+            .header("X-Emby-Authorization", authorization()?)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Quick Connect unavailable, {}", response.status()));
+        }
+
+        let json: Value = response.json().await?;
+        let secret = json["Secret"].as_str().ok_or(anyhow!("Secret not found"))?;
+        let code = json["Code"].as_str().ok_or(anyhow!("Code not found"))?;
+
+        Ok((secret.to_string(), code.to_string()))
+    }
+
+    // 查询 Quick Connect 是否已在其他客户端上授权
+    pub async fn quick_connect_authenticated(host: &str, secret: &str) -> Result<bool> {
+        let url = format!("{}/QuickConnect/Connect", host);
+
+        let response = client()
+            .get(url)
+            .query(&[("Secret", secret)])
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Request failed, {}", response.status()));
+        }
+
+        let json: Value = response.json().await?;
+        Ok(json["Authenticated"].as_bool().unwrap_or(false))
+    }
+
+    pub async fn authenticate_with_quick_connect(host: &str, secret: &str) -> Result<String> {
+        let url = format!("{}/Users/AuthenticateWithQuickConnect", host);
+
+        let response = client()
+            .post(url)
+            .header("X-Emby-Authorization", authorization()?)
+            .json(&json!({ "Secret": secret }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Authentication failed, {}", response.status()));
+        }
+
+        parse_auth(&response.json().await?)
+    }
+
+    // 获取服务器名称和版本，无需登录
+    pub async fn get_public_info(host: &str) -> Result<String> {
+        let url = format!("{}/emby/System/Info/Public", host);
+
+        let response = client().get(url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Request failed, {}", response.status()));
+        }
+
+        let json: Value = response.json().await?;
+        Ok(format!(
+            "{} {}",
+            json["ServerName"].as_str().unwrap_or_default(),
+            json["Version"].as_str().unwrap_or_default()
+        ))
+    }
+
+    // 检查 token 是否仍然有效
+    pub async fn check_token(host: &str, api_key: &str) -> Result<bool> {
+        let url = format!("{}/emby/System/Info", host);
+
+        let response = client()
+            .get(url)
+            .header("X-Emby-Token", api_key)
+            .send()
+            .await?;
+
+        Ok(response.status() != reqwest::StatusCode::UNAUTHORIZED)
+    }
+
     pub struct Id {
         pub user_id: String,
         pub play_session_id: String,
@@ -306,15 +833,22 @@ pub mod request {
     // 获取 UserId 和 PlaySessionId
     pub async fn get_user_id(host: &str, api_key: &str) -> Result<Id> {
         let params = [
-            ("X-Emby-Token", api_key),
-            ("X-Emby-Device-Id", &env::var("DEVICE_ID")?.to_string()),
-            ("X-Emby-Device-Name", &get_device_name()),
-            ("X-Emby-Client", "Emby"),
+            ("X-Emby-Device-Id", device_id()?),
+            ("X-Emby-Device-Name", &device_name()),
+            ("X-Emby-Client", CLIENT_NAME),
         ];
         let url = format!("{}/emby/Sessions", host);
 
-        let response = client().get(url).query(&params).send().await?;
+        let response = client()
+            .get(url)
+            .header("X-Emby-Token", api_key)
+            .query(&params)
+            .send()
+            .await?;
 
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(anyhow!("Token rejected by {}", host).context(Failure::Auth));
+        }
         if !response.status().is_success() {
             return Err(anyhow!("Request failed, {}", response.status()));
         }
@@ -329,13 +863,22 @@ pub mod request {
         })
     }
 
+    // 服务器记录的播放进度
+    pub struct ServerPosition {
+        pub ticks: Ticks,
+        // 最后播放时间，Unix 秒
+        pub last_played: Option<u64>,
+        // 条目总时长
+        pub runtime: Option<Ticks>,
+    }
+
     // 获取开播进度
     pub async fn get_start_position(
         host: &str,
         api_key: &str,
         item_id: &str,
         headers: HeaderMap,
-    ) -> Result<u64> {
+    ) -> Result<ServerPosition> {
         let user_id = get_user_id(host, api_key).await?.user_id;
 
         let url = format!("{}/emby/Users/{}/Items?Ids={}", host, user_id, item_id);
@@ -348,28 +891,66 @@ pub mod request {
 
         let json: serde_json::Value = response.json().await?;
 
-        let playback_ticks = json["Items"][0]["UserData"]["PlaybackPositionTicks"].as_u64();
-        Ok(playback_ticks.unwrap_or(0))
+        let user_data = &json["Items"][0]["UserData"];
+        let last_played = user_data["LastPlayedDate"]
+            .as_str()
+            .and_then(|date| chrono::DateTime::parse_from_rfc3339(date).ok())
+            .map(|date| date.timestamp().max(0) as u64);
+
+        Ok(ServerPosition {
+            ticks: Ticks(user_data["PlaybackPositionTicks"].as_u64().unwrap_or(0)),
+            last_played,
+            runtime: json["Items"][0]["RunTimeTicks"].as_u64().map(Ticks),
+        })
     }
 }
 
 pub mod property {
-    use std::io::{Read, Write};
+    #[cfg(unix)]
+    use crate::config::runtime_path;
+    use crate::config::MPVClient;
+    use crate::ticks::Ticks;
+    use anyhow::{anyhow, Context, Result};
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::{Duration, Instant};
 
-    #[cfg(windows)]
-    use serde_json::json;
     #[cfg(unix)]
     use std::os::unix::net::UnixStream;
     #[cfg(windows)]
     use std::os::windows::io::FromRawHandle;
+    #[cfg(unix)]
+    use std::sync::OnceLock;
     #[cfg(windows)]
     use windows::{core::*, Win32::Foundation::*, Win32::Storage::FileSystem::*};
 
     #[cfg(windows)]
-    pub fn get_time_pos_win() -> windows::core::Result<String> {
-        let pipe_name = r"\\.\pipe\mpvsocket";
+    pub fn ipc_server() -> &'static str {
+        r"\\.\pipe\mpvsocket"
+    }
 
-        let wide_pipe_name: Vec<u16> = pipe_name.encode_utf16().chain(std::iter::once(0)).collect();
+    // socket 放在只有当前用户能访问的运行时目录，其他用户不能连接或抢先创建
+    // 沙箱中的 mpv 看不到主机的 /tmp，改用双方共享的运行时目录
+    #[cfg(unix)]
+    pub fn ipc_server() -> &'static str {
+        static IPC_SERVER: OnceLock<String> = OnceLock::new();
+        IPC_SERVER.get_or_init(|| {
+            let path = match MPVClient::sandbox().zip(dirs::runtime_dir()) {
+                Some((sandbox, dir)) => dir.join(sandbox.runtime_dir()).join("mpvsocket"),
+                None => runtime_path().join("mpv-handler-ipc.sock"),
+            };
+            path.display().to_string()
+        })
+    }
+
+    #[cfg(windows)]
+    fn connect() -> Result<std::fs::File> {
+        let wide_pipe_name: Vec<u16> = ipc_server()
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
 
         let handle = unsafe {
             CreateFileW(
@@ -381,78 +962,118 @@ pub mod property {
                 FILE_ATTRIBUTE_NORMAL, // 改为普通文件属性
                 Some(HANDLE::default()),
             )
-        };
-
-        if handle.is_err() {
-            println!("Failed to open pipe: {:?}", handle.as_ref().err());
-            return Err(handle.err().unwrap());
         }
+        .map_err(|e| anyhow!("Failed to open pipe: {:?}", e))?;
 
-        let handle = handle.unwrap();
-        let mut file = unsafe { std::fs::File::from_raw_handle(handle.0 as *mut _) };
+        Ok(unsafe { std::fs::File::from_raw_handle(handle.0 as *mut _) })
+    }
 
-        let message = json!({
-            "command": ["get_property", "time-pos"]
-        });
+    #[cfg(unix)]
+    fn connect() -> Result<UnixStream> {
+        // 连接到 MPV 的 IPC socket
+        Ok(UnixStream::connect(ipc_server())?)
+    }
+
+    // 向 mpv 发送一条 IPC 命令并返回 data 字段
+    pub fn send_command(command: Value) -> Result<Value> {
+        static REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+        let request_id = REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+
+        let mut stream = connect()?;
 
         // 添加换行符
-        let message_str = message.to_string() + "\n";
-        // println!("Sending message: {}", message_str);
-        file.write_all(message_str.as_bytes()).map_err(|e| {
-            println!("Failed to write: {:?}", e);
-            Error::from_win32()
-        })?;
-
-        let mut response = String::new();
-        let mut buffer = [0; 1024];
+        let message = json!({ "command": command, "request_id": request_id }).to_string() + "\n";
+        stream.write_all(message.as_bytes())?;
+
+        // mpv 会向所有客户端广播事件，跳过与本次请求无关的行
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
         loop {
-            match file.read(&mut buffer) {
-                Ok(0) => break, // 读取结束
-                Ok(n) => {
-                    response.push_str(&String::from_utf8_lossy(&buffer[..n]));
-                    if response.ends_with('\n') {
-                        break; // 读取到换行符，认为响应结束
-                    }
-                }
-                Err(e) => {
-                    println!("Failed to read: {:?}", e);
-                    return Err(Error::from_win32());
-                }
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(anyhow!("mpv closed the IPC connection"));
             }
-        }
 
-        let time_pos: serde_json::Value =
-            serde_json::from_str(response.trim()).expect("No valid time-pos found.");
-        // println!("Received response: {}", time_pos["data"]);
-        let time_data = time_pos["data"].to_string();
+            let response: Value =
+                serde_json::from_str(line.trim()).context("Invalid IPC response")?;
+            if response["request_id"].as_u64() != Some(request_id) {
+                continue;
+            }
 
-        Ok(time_data)
+            return match response["error"].as_str() {
+                Some("success") => Ok(response["data"].clone()),
+                Some(err) => Err(anyhow!("mpv IPC error: {}", err)),
+                None => Err(anyhow!("Invalid IPC response")),
+            };
+        }
     }
 
-    #[cfg(unix)]
-    use anyhow::{Context, Result};
-    #[cfg(unix)]
-    pub fn get_time_pos_unix() -> Result<String> {
-        // 连接到 MPV 的 IPC socket
-        let mut stream = UnixStream::connect("/tmp/mpvsocket")?;
+    // 监听 mpv 的 script-message，返回每条消息的参数
+    // 连接在 mpv 退出前保持打开，调用方不再需要时直接丢弃接收端
+    pub fn subscribe(name: &str) -> Result<Receiver<Vec<String>>> {
+        let stream = connect()?;
+        let name = name.to_string();
+        let (tx, rx) = mpsc::channel();
 
-        // 构造要发送的命令
-        let command = r#"{ "command": ["get_property", "time-pos"] }"#;
+        std::thread::spawn(move || {
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                let Ok(event) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                if event["event"] != "client-message" || event["args"][0] != name.as_str() {
+                    continue;
+                }
 
-        // 发送命令
-        stream.write_all(command.as_bytes())?;
-        stream.write_all(b"\n")?; // 确保以换行符结束
+                let args = event["args"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .skip(1)
+                    .filter_map(|arg| arg.as_str().map(str::to_string))
+                    .collect();
+                if tx.send(args).is_err() {
+                    break;
+                }
+            }
+        });
 
-        // 读取响应
-        let mut response = String::new();
-        stream.read_to_string(&mut response)?;
+        Ok(rx)
+    }
 
-        // println!("Received response: {}", response);
-        let time_pos: serde_json::Value =
-            serde_json::from_str(response.trim()).context("No valid time-pos found.")?;
-        // println!("Received response: {}", time_pos["data"]);
-        let time_data = time_pos["data"].to_string();
+    // 等待 mpv 创建 IPC socket
+    pub fn wait_ready(timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            match connect() {
+                Ok(_) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => {
+                    let e = e.context(format!("mpv IPC server {} not reachable", ipc_server()));
+                    return Err(match MPVClient::sandbox() {
+                        Some(sandbox) => e.context(format!(
+                            "mpv runs in a sandbox ({:?}) and must be able to write to {}",
+                            sandbox,
+                            ipc_server()
+                        )),
+                        None => e,
+                    });
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    pub fn get_time_pos() -> Result<Ticks> {
+        let time_pos = send_command(json!(["get_property", "time-pos"]))?;
+        Ticks::from_mpv(&time_pos.to_string()).context("Failed to parse ticks")
+    }
 
-        Ok(time_data)
+    // 获取条目时长
+    pub fn get_duration() -> Result<Ticks> {
+        let duration = send_command(json!(["get_property", "duration"]))?;
+        Ticks::from_mpv(&duration.to_string()).ok_or_else(|| anyhow!("Duration is not available"))
     }
 }
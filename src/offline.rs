@@ -0,0 +1,423 @@
+// 离线下载和播放，进度记入观看记录，在服务器可访问时再回传
+use crate::cli::{Failure, Options};
+use crate::config::{library_dir, Config};
+use crate::history::{self, Session};
+use crate::log;
+use crate::network::extractor::same_host;
+use crate::network::property;
+use crate::network::request::{self, construct_headers, get_user_id};
+use crate::player;
+use crate::resume::{self, Start};
+use crate::shutdown::Watch;
+use crate::ticks::Ticks;
+use anyhow::{anyhow, Context, Result};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const META_FILE: &str = "meta.json";
+const CHAPTERS_FILE: &str = "chapters.ffmetadata";
+
+// 已下载的条目，保存在条目目录的 meta.json 中
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub host: String,
+    pub item_id: String,
+    pub media_source_id: String,
+    pub title: String,
+    // 视频和字幕的文件名，相对于条目目录
+    pub file: String,
+    #[serde(default)]
+    pub subtitles: Vec<String>,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    pub resume_ticks: Ticks,
+    #[serde(default)]
+    pub runtime: Option<Ticks>,
+    #[serde(default)]
+    pub complete: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Chapter {
+    pub name: String,
+    pub start_ticks: Ticks,
+}
+
+// 下载条目的视频、外挂字幕和元数据，再次运行时从中断处继续
+pub async fn download(
+    host: &str,
+    api_key: &str,
+    item_id: &str,
+    media_source_id: &str,
+    subfile_url: &str,
+    options: Options,
+) -> Result<()> {
+    if options.dry_run {
+        log!(
+            "GET {}/emby/Items/{}/Download?MediaSourceId={}",
+            host,
+            item_id,
+            media_source_id
+        );
+        return Ok(());
+    }
+
+    let user_id = get_user_id(host, api_key).await?.user_id;
+    let headers = construct_headers(api_key, &user_id).await?;
+
+    let sources = request::get_media_sources(host, item_id, &user_id, headers.clone()).await?;
+    let source = sources
+        .iter()
+        .find(|source| source.id == media_source_id)
+        .or(sources.first())
+        .ok_or_else(|| anyhow!("No media source found for {}", item_id))?;
+
+    let title = request::get_chapter_info(host, item_id, headers.clone()).await?;
+    let chapters = request::get_chapters(host, &user_id, item_id, headers.clone())
+        .await
+        .unwrap_or_default();
+    let position = request::get_start_position(host, api_key, item_id, headers.clone()).await?;
+
+    let dir = entry_dir(host, item_id)?;
+    std::fs::create_dir_all(&dir)?;
+
+    let extension = source.container.as_deref().unwrap_or("mkv");
+    let mut entry = Entry {
+        host: host.to_string(),
+        item_id: item_id.to_string(),
+        media_source_id: source.id.clone(),
+        title: title.trim_matches('"').to_string(),
+        file: format!("video.{}", extension),
+        subtitles: Vec::new(),
+        chapters: chapters
+            .into_iter()
+            .map(|(name, start_ticks)| Chapter { name, start_ticks })
+            .collect(),
+        resume_ticks: position.ticks,
+        runtime: position.runtime,
+        complete: false,
+    };
+    save_entry(&dir, &entry)?;
+
+    log!("正在下载 {} 到 {}", entry.title, dir.display());
+
+    // 下载到 .part 文件，完成后再改名
+    let part = dir.join(format!("{}.part", entry.file));
+    let download_url = format!(
+        "{}/emby/Items/{}/Download?MediaSourceId={}",
+        host, item_id, source.id
+    );
+    if let Err(e) = request::download(&download_url, headers.clone(), &part).await {
+        // 没有下载权限时改用推流链接，两者都是原始文件
+        log!("下载接口不可用，改用推流链接: {}", e);
+        let stream_url = request::stream_url(host, item_id, source);
+        request::download(&stream_url, headers.clone(), &part).await?;
+    }
+    std::fs::rename(&part, dir.join(&entry.file))?;
+
+    // 外挂字幕
+    let mut subtitle_urls: Vec<(String, String)> = source
+        .media_streams
+        .iter()
+        .filter(|stream| stream.kind == "Subtitle" && stream.is_external)
+        .map(|stream| {
+            let url = request::subtitle_url(host, item_id, &source.id, stream);
+            let extension = url.rsplit('.').next().unwrap_or("srt").to_string();
+            let name = match &stream.language {
+                Some(language) => format!(
+                    "sub{}.{}.{}",
+                    stream.index.unwrap_or(0),
+                    language,
+                    extension
+                ),
+                None => format!("sub{}.{}", stream.index.unwrap_or(0), extension),
+            };
+            (name, url)
+        })
+        .collect();
+    if !subfile_url.is_empty() {
+        let extension = Path::new(subfile_url.split('?').next().unwrap_or_default())
+            .extension()
+            .map_or("srt".to_string(), |e| e.to_string_lossy().into_owned());
+        subtitle_urls.push((format!("link.{}", extension), subfile_url.to_string()));
+    }
+
+    for (name, url) in subtitle_urls {
+        let path = dir.join(&name);
+        // 字幕很小，每次重新下载
+        let _ = std::fs::remove_file(&path);
+        // token 只发给 Emby 服务器，链接中其他服务器的字幕不带请求头
+        let headers = match same_host(&url, host) {
+            true => headers.clone(),
+            false => HeaderMap::new(),
+        };
+        match request::download(&url, headers, &path).await {
+            Ok(()) => entry.subtitles.push(name),
+            Err(e) => log!("下载字幕 {} 失败: {}", name, e),
+        }
+    }
+
+    entry.complete = true;
+    save_entry(&dir, &entry)?;
+
+    log!("下载完成: {}", entry.title);
+
+    Ok(())
+}
+
+// 列出已下载的条目
+pub fn list() -> Result<()> {
+    let entries = entries()?;
+    if entries.is_empty() {
+        log!("没有已下载的条目");
+    }
+
+    for (_, entry) in &entries {
+        log!(
+            "{}  {}  [{}]  {}{}",
+            entry.item_id,
+            entry.title,
+            entry.host,
+            entry.resume_ticks,
+            if entry.complete { "" } else { "  (未完成)" }
+        );
+    }
+
+    Ok(())
+}
+
+// 用 mpv 播放已下载的条目，退出后写入观看记录并尝试回传进度
+pub async fn play(item_id: &str, options: Options) -> Result<()> {
+    let (dir, mut entry) = entries()?
+        .into_iter()
+        .find(|(_, entry)| entry.item_id == item_id)
+        .ok_or_else(|| anyhow!("{} has not been downloaded", item_id))
+        .context(Failure::Parse)?;
+    if !entry.complete {
+        return Err(anyhow!("Download of {} is not complete", item_id));
+    }
+
+    // 离线期间可能在其他设备上播放过，以本地最近的记录为准
+    let resume_ticks =
+        history::position(&entry.host, &entry.item_id).map_or(entry.resume_ticks, |p| p.ticks);
+    let resume_policy = Config::load()?.resume;
+    let start = resume::decide(&resume_policy, resume_ticks, entry.runtime);
+
+    let mut mpv = player::mpv_command(options)?;
+    if !entry.chapters.is_empty() {
+        let chapters = dir.join(CHAPTERS_FILE);
+        std::fs::write(&chapters, ffmetadata(&entry.chapters, entry.runtime))?;
+        mpv.arg(format!("--chapters-file={}", chapters.display()));
+    }
+
+    // 外挂字幕随 mpv 启动加载，只对这个文件生效
+    for subtitle in &entry.subtitles {
+        mpv.arg(format!("--sub-file={}", dir.join(subtitle).display()));
+    }
+
+    let url = dir.join(&entry.file);
+    let title = entry.title.clone();
+    let loadfile = move |start: Ticks| {
+        json!({
+            "name": "loadfile",
+            "url": url,
+            "flags": "replace",
+            "options": {
+                "force-media-title": title,
+                "start": start.to_mpv(),
+            },
+        })
+    };
+
+    if options.dry_run || options.verbose {
+        log!("{}", player::command_line(&mpv));
+        if let Start::Ask(resume) = start {
+            log!("询问是否从 {} 继续播放", resume);
+        }
+        let (Start::At(ticks) | Start::Ask(ticks)) = start;
+        log!("IPC {}", loadfile(ticks));
+    }
+    if options.dry_run {
+        return Ok(());
+    }
+
+    // 启动 mpv 之前开始监听退出信号，等待选择时被终止也能清理
+    let mut watch = Watch::new();
+    let started_at = history::now();
+    let mut child = mpv
+        .spawn()
+        .context("Failed to start mpv")
+        .context(Failure::Player)?;
+
+    // 等待 mpv 启动和菜单选择时会阻塞，不占用异步运行时的线程
+    let load = tokio::task::spawn_blocking(move || -> Result<Ticks> {
+        property::wait_ready(Duration::from_secs(10))?;
+        let start_ticks = match start {
+            Start::At(ticks) => ticks,
+            Start::Ask(resume) => resume::ask(resume, &resume_policy)?,
+        };
+        property::send_command(loadfile(start_ticks))?;
+        Ok(start_ticks)
+    });
+    let start_ticks = match watch.guard(async { load.await? }).await {
+        Ok(start_ticks) => start_ticks,
+        Err(e) if watch.interrupted => {
+            watch.finish(&mut child).await;
+            return Err(e);
+        }
+        Err(e) => {
+            let _ = child.kill();
+            return Err(e.context(Failure::Player));
+        }
+    };
+
+    let mut ticks = start_ticks;
+    let mut duration = None;
+    let mut last_print = Instant::now();
+    while watch.running(&mut child).await {
+        if last_print.elapsed() >= Duration::from_secs(10) {
+            if let Ok(position) = property::get_time_pos() {
+                ticks = position;
+                history::save_position(&entry.host, &entry.item_id, ticks);
+            }
+            if duration.is_none() {
+                duration = property::get_duration().ok();
+            }
+            last_print = Instant::now();
+        }
+    }
+
+    if let Some(position) = watch.final_position().await {
+        ticks = position;
+    }
+    watch.finish(&mut child).await;
+
+    entry.resume_ticks = ticks;
+    save_entry(&dir, &entry)?;
+
+    history::record(&Session {
+        host: entry.host.clone(),
+        item_id: entry.item_id.clone(),
+        media_source_id: entry.media_source_id.clone(),
+        title: entry.title.clone(),
+        start_ticks,
+        stop_ticks: ticks,
+        duration_ticks: duration,
+        started_at,
+        stopped_at: history::now(),
+        pending: !options.no_report,
+        offline: true,
+    })?;
+    // 被信号终止时不等待回传，留到下次启动
+    if !options.no_report && !watch.interrupted {
+        history::replay().await;
+    }
+
+    Ok(())
+}
+
+// 条目目录，如 emby_example_com-12345
+fn entry_dir(host: &str, item_id: &str) -> Result<PathBuf> {
+    let host: String = host
+        .split("://")
+        .last()
+        .unwrap_or(host)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    Ok(library_dir()?.join(format!("{}-{}", host, item_id)))
+}
+
+fn entries() -> Result<Vec<(PathBuf, Entry)>> {
+    let mut entries: Vec<(PathBuf, Entry)> = std::fs::read_dir(library_dir()?)?
+        .filter_map(|dir| dir.ok().map(|dir| dir.path()))
+        .filter_map(|dir| {
+            let meta = std::fs::read_to_string(dir.join(META_FILE)).ok()?;
+            let entry = serde_json::from_str(&meta).ok()?;
+            Some((dir, entry))
+        })
+        .collect();
+    entries.sort_by(|(_, a), (_, b)| a.title.cmp(&b.title));
+
+    Ok(entries)
+}
+
+fn save_entry(dir: &Path, entry: &Entry) -> Result<()> {
+    std::fs::write(dir.join(META_FILE), serde_json::to_string_pretty(entry)?)
+        .context("Failed to save download metadata")
+}
+
+// 生成 mpv --chapters-file 可读的 FFMETADATA
+fn ffmetadata(chapters: &[Chapter], runtime: Option<Ticks>) -> String {
+    let mut text = String::from(";FFMETADATA1\n");
+    for (i, chapter) in chapters.iter().enumerate() {
+        // 最后一章到条目结束
+        let end = match chapters.get(i + 1) {
+            Some(next) => next.start_ticks,
+            None => runtime
+                .filter(|runtime| *runtime > chapter.start_ticks)
+                .unwrap_or(chapter.start_ticks),
+        };
+        text.push_str(&format!(
+            "[CHAPTER]\nTIMEBASE=1/10000000\nSTART={}\nEND={}\ntitle={}\n",
+            chapter.start_ticks.0,
+            end.0,
+            escape(&chapter.name)
+        ));
+    }
+    text
+}
+
+// FFMETADATA 中的 =、;、#、\ 和换行需要用反斜杠转义
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ffmetadata, Chapter};
+    use crate::ticks::Ticks;
+
+    fn chapter(name: &str, start: u64) -> Chapter {
+        Chapter {
+            name: name.to_string(),
+            start_ticks: Ticks(start),
+        }
+    }
+
+    #[test]
+    fn ffmetadata_ends_last_chapter_at_runtime() {
+        let chapters = [chapter("Opening", 0), chapter("Part A", 900_000_000)];
+        let text = ffmetadata(&chapters, Some(Ticks(14_400_000_000)));
+
+        assert_eq!(
+            text,
+            ";FFMETADATA1\n\
+             [CHAPTER]\nTIMEBASE=1/10000000\nSTART=0\nEND=900000000\ntitle=Opening\n\
+             [CHAPTER]\nTIMEBASE=1/10000000\nSTART=900000000\nEND=14400000000\ntitle=Part A\n"
+        );
+    }
+
+    #[test]
+    fn ffmetadata_without_runtime_keeps_last_chapter_empty() {
+        let text = ffmetadata(&[chapter("End", 600)], None);
+        assert!(text.contains("START=600\nEND=600\n"));
+    }
+
+    #[test]
+    fn ffmetadata_escapes_special_characters() {
+        let text = ffmetadata(&[chapter("a=b;c#d\\e\nf", 0)], Some(Ticks(10)));
+        assert!(text.ends_with("title=a\\=b\\;c\\#d\\\\e\\\nf\n"));
+    }
+}
@@ -0,0 +1,62 @@
+// 在 mpv 的 OSD 上显示菜单，按数字键选择
+use crate::network::property;
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+const MENU_SECTION: &str = "mpv-handler-menu";
+const MENU_MESSAGE: &str = "mpv-handler-choice";
+
+// 显示选项列表，按数字键选择，回车或超时返回 default
+pub fn menu(title: &str, labels: &[String], default: usize, timeout: Duration) -> Result<usize> {
+    let labels = &labels[..labels.len().min(9)];
+    let messages = property::subscribe(MENU_MESSAGE)?;
+
+    let mut bindings: Vec<String> = (0..labels.len())
+        .map(|i| format!("{} script-message {} {}", i + 1, MENU_MESSAGE, i))
+        .collect();
+    bindings.push(format!("ENTER script-message {} {}", MENU_MESSAGE, default));
+
+    let lines: Vec<String> = labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| {
+            let marker = if i == default { ">" } else { " " };
+            format!("{} {}: {}", marker, i + 1, label)
+        })
+        .collect();
+
+    property::send_command(json!([
+        "define-section",
+        MENU_SECTION,
+        bindings.join("\n"),
+        "force"
+    ]))?;
+    property::send_command(json!(["enable-section", MENU_SECTION]))?;
+    property::send_command(json!([
+        "show-text",
+        format!(
+            "{} (1-{}), Enter for the default\n{}",
+            title,
+            labels.len(),
+            lines.join("\n")
+        ),
+        timeout.as_millis() as u64
+    ]))?;
+
+    let choice = match messages.recv_timeout(timeout) {
+        Ok(args) => args
+            .first()
+            .and_then(|arg| arg.parse::<usize>().ok())
+            .filter(|&i| i < labels.len())
+            .unwrap_or(default),
+        Err(RecvTimeoutError::Timeout) => default,
+        Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("mpv exited")),
+    };
+
+    property::send_command(json!(["disable-section", MENU_SECTION]))?;
+    property::send_command(json!(["show-text", "", 1]))?;
+
+    Ok(choice)
+}
@@ -0,0 +1,734 @@
+use crate::cli::{Failure, Options};
+use crate::config::{runtime_dir, Config, InstanceMode, MPVClient, ResumePolicy};
+use crate::history::{self, Session};
+use crate::network::extractor;
+use crate::network::property::{self, ipc_server};
+use crate::network::request::{
+    self, construct_headers, get_proxy, get_ua, get_user_id, playing_status, PlayMethod,
+    PlaySession, PlayStatus,
+};
+use crate::resume::{self, Start};
+use crate::shutdown::Watch;
+use crate::source;
+use crate::ticks::Ticks;
+use crate::{log, verbose};
+use anyhow::{anyhow, Context, Result};
+use reqwest::header::HeaderMap;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use url::Url;
+
+// 一次播放所需的全部信息
+pub struct Media {
+    pub video_url: String,
+    pub subfile_url: String,
+    pub host: String,
+    pub item_id: String,
+    pub media_source_id: String,
+    pub api_key: String,
+    // 开启版本选择时的全部版本，否则为空
+    pub versions: Vec<Version>,
+}
+
+pub struct Version {
+    pub media_source_id: String,
+    pub video_url: String,
+    pub label: String,
+}
+
+// 通过 PlaybackInfo 选择媒体源并生成推流链接
+// media_source_id 为 None 时按配置的策略选择
+pub async fn item_media(
+    host: &str,
+    api_key: &str,
+    user_id: &str,
+    item_id: &str,
+    media_source_id: Option<String>,
+) -> Result<Media> {
+    let policy = Config::load()?.media_source;
+    let headers = construct_headers(api_key, user_id).await?;
+    let sources = request::get_media_sources(host, item_id, user_id, headers).await?;
+
+    let index = match media_source_id {
+        Some(id) => sources.iter().position(|s| s.id == id),
+        None => (!sources.is_empty()).then(|| source::select(&sources, &policy)),
+    }
+    .ok_or_else(|| anyhow!("No media source found for {}", item_id))?;
+
+    let versions: Vec<Version> = sources
+        .iter()
+        .map(|source| Version {
+            media_source_id: source.id.clone(),
+            video_url: request::stream_url(host, item_id, source),
+            label: source::label(source),
+        })
+        .collect();
+    let chosen = &versions[index];
+
+    Ok(Media {
+        video_url: chosen.video_url.clone(),
+        subfile_url: String::new(),
+        host: host.to_string(),
+        item_id: item_id.to_string(),
+        media_source_id: chosen.media_source_id.clone(),
+        api_key: api_key.to_string(),
+        versions: match policy.picker && versions.len() > 1 {
+            true => versions,
+            false => Vec::new(),
+        },
+    })
+}
+
+// 启动 mpv 并在播放期间回传进度，mpv 退出后返回
+// start_ticks 为 None 时从服务器读取播放进度
+// queue 为单实例模式下其他进程转交的条目，按配置替换当前条目或加入播放列表
+pub async fn play(
+    media: Media,
+    start_ticks: Option<Ticks>,
+    mut queue: Option<UnboundedReceiver<Media>>,
+    options: Options,
+) -> Result<()> {
+    // 先开始监听退出信号，请求服务器和等待选择时被终止也能清理
+    let mut watch = Watch::new();
+    let prepared = watch.guard(prepare(media, start_ticks, options)).await?;
+    let mut mpv = mpv_command(options)?;
+
+    if options.dry_run || options.verbose {
+        log!("{}", command_line(&mpv));
+        for version in &prepared.media.versions {
+            log!("版本 {}: {}", version.media_source_id, version.label);
+        }
+        if let Start::Ask(resume) = prepared.start {
+            log!("询问是否从 {} 继续播放", resume);
+        }
+        let Media {
+            video_url,
+            subfile_url,
+            api_key,
+            ..
+        } = &prepared.media;
+        let (Start::At(start) | Start::Ask(start)) = prepared.start;
+        let loadfile = prepared.loadfile(video_url, subfile_url, start, "replace");
+        log!(
+            "IPC {}",
+            loadfile.to_string().replace(api_key.as_str(), "***")
+        );
+    }
+
+    if options.dry_run {
+        let Media { host, item_id, .. } = &prepared.media;
+        let transcode = prepared.needs_transcode(&prepared.media.media_source_id);
+        if transcode {
+            log!(
+                "POST {}/emby/Items/{}/PlaybackInfo (transcode, MaxStreamingBitrate={})",
+                host,
+                item_id,
+                prepared.max_bitrate.unwrap_or_default()
+            );
+        }
+        if !options.no_report {
+            log!("POST {}/emby/Sessions/Playing", host);
+            log!("POST {}/emby/Sessions/Playing/Progress (every 10s)", host);
+            log!("POST {}/emby/Sessions/Playing/Stopped", host);
+        }
+        if transcode {
+            log!("DELETE {}/emby/Videos/ActiveEncodings", host);
+        }
+        return Ok(());
+    }
+
+    // 启动子进程
+    let mut child: Child = mpv
+        .spawn()
+        .context("Failed to start mpv")
+        .context(Failure::Player)?;
+
+    let loaded = match watch.guard(load(prepared, false)).await {
+        Ok(loaded) => loaded,
+        Err(e) if watch.interrupted => {
+            watch.finish(&mut child).await;
+            return Err(e);
+        }
+        Err(e) => {
+            let _ = child.kill();
+            return Err(e.context(Failure::Player));
+        }
+    };
+
+    // 不回传进度时只等待 mpv 退出
+    let report = !options.no_report;
+    let mode = Config::load()?.single_instance;
+
+    // 播放列表中每一项对应的条目，mpv 切换到其他项时改为回传该条目的进度
+    let mut entries: HashMap<i64, Loaded> = HashMap::from([(0, loaded.clone())]);
+    let mut index = 0;
+    let mut playing = Playing::new(loaded);
+    playing.start(report).await;
+
+    let mut last_print = Instant::now();
+
+    // 上传播放进度
+    loop {
+        let incoming = tokio::select! {
+            running = watch.running(&mut child) => match running {
+                true => None,
+                false => break,
+            },
+            media = next(&mut queue) => Some(media),
+        };
+
+        if let Some(media) = incoming {
+            let append = mode == Some(InstanceMode::Append);
+            let loaded = watch
+                .guard(async {
+                    let prepared = prepare(media, None, options).await?;
+                    // 替换前先记下当前条目的位置
+                    if !append {
+                        playing.progress(report).await;
+                    }
+                    load(prepared, append).await
+                })
+                .await;
+            let loaded = match loaded {
+                Ok(loaded) => loaded,
+                // 收到信号时结束播放，回传当前条目的 Stop
+                Err(_) if watch.interrupted => break,
+                Err(e) => {
+                    log!("播放转交的条目失败: {:#}", e);
+                    continue;
+                }
+            };
+
+            if append {
+                match property::send_command(json!(["get_property", "playlist-count"])) {
+                    Ok(count) => {
+                        log!("已加入播放列表: {}", loaded.title);
+                        entries.insert(count.as_i64().unwrap_or(1) - 1, loaded);
+                    }
+                    Err(e) => log!("读取播放列表失败: {}", e),
+                }
+                continue;
+            }
+
+            playing.stop(&watch, report).await;
+            entries = HashMap::from([(0, loaded.clone())]);
+            index = 0;
+            playing = Playing::new(loaded);
+            playing.start(report).await;
+            last_print = Instant::now();
+            continue;
+        }
+
+        // mpv 播放到了列表中的其他条目
+        if entries.len() > 1 {
+            let position = property::send_command(json!(["get_property", "playlist-pos"]))
+                .ok()
+                .and_then(|position| position.as_i64())
+                .filter(|position| *position != index && entries.contains_key(position));
+            if let Some(position) = position {
+                index = position;
+                playing.stop(&watch, report).await;
+                playing = Playing::new(entries[&position].clone());
+                playing.start(report).await;
+                last_print = Instant::now();
+            }
+        }
+
+        if last_print.elapsed() >= Duration::from_secs(10) {
+            playing.progress(report).await;
+            last_print = Instant::now();
+        }
+    }
+
+    if let Some(position) = watch.final_position().await {
+        playing.ticks = position;
+    }
+    playing.stop(&watch, report).await;
+
+    watch.finish(&mut child).await;
+
+    Ok(())
+}
+
+// 单实例模式下等待下一个转交的条目，没有时永远等待
+async fn next(queue: &mut Option<UnboundedReceiver<Media>>) -> Media {
+    if let Some(media) = match queue {
+        Some(queue) => queue.recv().await,
+        None => None,
+    } {
+        return media;
+    }
+
+    *queue = None;
+    std::future::pending().await
+}
+
+// 启动 mpv 之前读取标题、播放进度和码率
+struct Prepared {
+    media: Media,
+    play_session_id: String,
+    user_id: String,
+    headers: HeaderMap,
+    title: String,
+    start: Start,
+    resume_policy: ResumePolicy,
+    max_bitrate: Option<u64>,
+    bitrates: HashMap<String, u64>,
+}
+
+async fn prepare(media: Media, start_ticks: Option<Ticks>, options: Options) -> Result<Prepared> {
+    let Media {
+        host,
+        item_id,
+        media_source_id,
+        api_key,
+        ..
+    } = &media;
+
+    // 设置请求头
+    let user_id = get_user_id(host, api_key).await?;
+    let headers = construct_headers(api_key, &user_id.user_id).await?;
+
+    // 获取重定向之后的推流链接
+    // let video_url = get_redirect(
+    //     format!(
+    //         "{}&PlaySessionId={}",
+    //         video_url,
+    //         user_id.unwrap().play_session_id
+    //     ),
+    //     headers.clone(),
+    // )
+    // .await;
+
+    // 显示媒体标题信息
+    let title = request::get_chapter_info(host, item_id, headers.clone()).await?;
+
+    // 获取视频播放进度
+    // 没有指定开始位置时，按配置的策略决定是否继续播放
+    let resume_policy = Config::load()?.resume;
+    let start = match start_ticks {
+        Some(ticks) => Start::At(ticks),
+        None => {
+            let sync = !options.dry_run && !options.no_report;
+            let session = PlaySession {
+                id: user_id.play_session_id.clone(),
+                method: PlayMethod::DirectStream,
+            };
+            let (resume, runtime) = resume_position(
+                host,
+                api_key,
+                item_id,
+                media_source_id,
+                &session,
+                sync,
+                headers.clone(),
+            )
+            .await;
+            resume::decide(&resume_policy, resume, runtime)
+        }
+    };
+
+    // 超过码率上限的版本改用服务器转码
+    let max_bitrate = Config::load()?.max_streaming_bitrate(host);
+    let bitrates: HashMap<String, u64> = match max_bitrate {
+        Some(_) => {
+            match request::get_media_sources(host, item_id, &user_id.user_id, headers.clone()).await
+            {
+                Ok(sources) => sources
+                    .into_iter()
+                    .filter_map(|source| Some((source.id, source.bitrate?)))
+                    .collect(),
+                Err(e) => {
+                    log!("获取媒体源码率失败: {}", e);
+                    HashMap::new()
+                }
+            }
+        }
+        None => HashMap::new(),
+    };
+
+    Ok(Prepared {
+        play_session_id: user_id.play_session_id,
+        user_id: user_id.user_id,
+        headers,
+        title,
+        start,
+        resume_policy,
+        max_bitrate,
+        bitrates,
+        media,
+    })
+}
+
+impl Prepared {
+    fn needs_transcode(&self, id: &str) -> bool {
+        self.max_bitrate
+            .is_some_and(|max| self.bitrates.get(id).is_some_and(|&bitrate| bitrate > max))
+    }
+
+    // 请求头、标题、进度和字幕都作为这一项的选项，不影响列表中的其他条目
+    // token 只随推流的 loadfile 传给 mpv，不设置到全局的 http-header-fields
+    fn loadfile(&self, video_url: &str, subfile_url: &str, start: Ticks, flags: &str) -> Value {
+        let mut options = json!({
+            "http-header-fields": format!("X-Emby-Token: {}", self.media.api_key),
+            "force-media-title": self.title.trim_matches('"'),
+            "start": start.to_mpv(),
+        });
+        if !subfile_url.is_empty() {
+            options["sub-files-append"] = json!(subfile_url);
+        }
+        json!({
+            "name": "loadfile",
+            "url": video_url,
+            "flags": flags,
+            "options": options,
+        })
+    }
+}
+
+// 交给 mpv 播放，append 为 true 时加入播放列表末尾
+// 加入列表的条目不弹出版本和继续播放的询问，使用默认版本和播放进度
+async fn load(prepared: Prepared, append: bool) -> Result<Loaded> {
+    let media = &prepared.media;
+
+    // 有多个版本时先在 mpv 中选择
+    let (chosen, start_ticks) = match (append, prepared.start) {
+        (true, Start::At(ticks) | Start::Ask(ticks)) => (None, ticks),
+        (false, start) => {
+            let default = media
+                .versions
+                .iter()
+                .position(|version| version.media_source_id == media.media_source_id)
+                .unwrap_or(0);
+            let labels: Vec<String> = media.versions.iter().map(|v| v.label.clone()).collect();
+            let resume_policy = prepared.resume_policy.clone();
+
+            // 等待 mpv 启动和菜单选择时会阻塞，不占用异步运行时的线程
+            let (index, start_ticks) = tokio::task::spawn_blocking(move || -> Result<_> {
+                property::wait_ready(Duration::from_secs(10))?;
+                let index = match labels.len() > 1 {
+                    true => Some(source::pick(&labels, default)?),
+                    false => None,
+                };
+                let start_ticks = match start {
+                    Start::At(ticks) => ticks,
+                    Start::Ask(resume) => resume::ask(resume, &resume_policy)?,
+                };
+                Ok((index, start_ticks))
+            })
+            .await??;
+
+            (
+                index.and_then(|index| media.versions.get(index)),
+                start_ticks,
+            )
+        }
+    };
+
+    // 进度按实际播放的版本和选择的开始位置回传
+    let (mut video_url, media_source_id) = match chosen {
+        Some(version) => (version.video_url.clone(), version.media_source_id.clone()),
+        None => (media.video_url.clone(), media.media_source_id.clone()),
+    };
+
+    let mut session = PlaySession {
+        id: prepared.play_session_id.clone(),
+        method: PlayMethod::DirectStream,
+    };
+    if let Some(max_bitrate) = prepared
+        .max_bitrate
+        .filter(|_| prepared.needs_transcode(&media_source_id))
+    {
+        log!("码率超过 {} bps，使用服务器转码", max_bitrate);
+        match request::get_transcode(
+            &media.host,
+            &media.item_id,
+            &prepared.user_id,
+            &media_source_id,
+            max_bitrate,
+            prepared.headers.clone(),
+        )
+        .await
+        {
+            Ok((url, transcode)) => {
+                video_url = extractor::strip_api_key(&url, &media.api_key).unwrap_or(url);
+                verbose!("转码链接: {}", video_url);
+                session = transcode;
+            }
+            Err(e) => log!("请求转码失败，改为直接播放: {}", e),
+        }
+    }
+
+    let subfile_url = match subtitle(media).await {
+        Ok(subfile_url) => subfile_url,
+        Err(e) => {
+            log!("下载字幕失败，不加载外挂字幕: {}", e);
+            String::new()
+        }
+    };
+    let flags = match append {
+        true => "append-play",
+        false => "replace",
+    };
+    let loadfile = prepared.loadfile(&video_url, &subfile_url, start_ticks, flags);
+    tokio::task::spawn_blocking(move || property::send_command(loadfile)).await??;
+
+    Ok(Loaded {
+        host: media.host.clone(),
+        item_id: media.item_id.clone(),
+        media_source_id,
+        title: prepared.title.trim_matches('"').to_string(),
+        session,
+        headers: prepared.headers,
+        start_ticks,
+    })
+}
+
+// 请求头对这一项的字幕同样生效，其他服务器的字幕先下载到本地，不把 token 发给它
+async fn subtitle(media: &Media) -> Result<String> {
+    let subfile_url = &media.subfile_url;
+    if subfile_url.is_empty() || extractor::same_host(subfile_url, &media.host) {
+        return Ok(subfile_url.clone());
+    }
+
+    let url = Url::parse(subfile_url)?;
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("subtitle.srt")
+        .replace(
+            |c: char| !c.is_ascii_alphanumeric() && !".-_".contains(c),
+            "_",
+        );
+    let path = runtime_dir()?.join(format!("subtitle-{}-{}", media.item_id, name));
+
+    let _ = std::fs::remove_file(&path);
+    request::download(subfile_url, HeaderMap::new(), &path).await?;
+    verbose!("字幕已下载到 {}", path.display());
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+// 已经交给 mpv 的条目
+#[derive(Clone)]
+struct Loaded {
+    host: String,
+    item_id: String,
+    media_source_id: String,
+    title: String,
+    session: PlaySession,
+    headers: HeaderMap,
+    start_ticks: Ticks,
+}
+
+// 正在回传进度的条目
+struct Playing {
+    loaded: Loaded,
+    ticks: Ticks,
+    duration: Option<Ticks>,
+    started_at: u64,
+}
+
+impl Playing {
+    fn new(loaded: Loaded) -> Playing {
+        Playing {
+            ticks: loaded.start_ticks,
+            loaded,
+            duration: None,
+            started_at: history::now(),
+        }
+    }
+
+    async fn status(&self, status: PlayStatus) -> Result<()> {
+        let loaded = &self.loaded;
+        playing_status(
+            self.ticks,
+            &loaded.host,
+            &loaded.item_id,
+            &loaded.session,
+            &loaded.media_source_id,
+            status,
+            loaded.headers.clone(),
+        )
+        .await
+    }
+
+    // 标记播放开始
+    async fn start(&self, report: bool) {
+        if report {
+            let _ = self.status(PlayStatus::Play).await;
+        }
+    }
+
+    // 读取当前位置并更新进度
+    async fn progress(&mut self, report: bool) {
+        if let Ok(position) = property::get_time_pos() {
+            self.ticks = position;
+            history::save_position(&self.loaded.host, &self.loaded.item_id, self.ticks);
+            if report {
+                let _ = self.status(PlayStatus::Progress).await;
+            }
+        } else {
+            log!("更新播放时间失败")
+        }
+        if self.duration.is_none() {
+            self.duration = property::get_duration().ok();
+        }
+    }
+
+    // 标记播放结束，失败时留在观看记录中等待下次回传
+    async fn stop(self, watch: &Watch, report: bool) {
+        let pending = report && watch.bounded(self.status(PlayStatus::Stop)).await.is_err();
+
+        let Loaded {
+            host,
+            item_id,
+            media_source_id,
+            title,
+            session,
+            headers,
+            start_ticks,
+        } = self.loaded;
+        if let Err(e) = history::record(&Session {
+            host: host.clone(),
+            item_id,
+            media_source_id,
+            title,
+            start_ticks,
+            stop_ticks: self.ticks,
+            duration_ticks: self.duration,
+            started_at: self.started_at,
+            stopped_at: history::now(),
+            pending,
+            offline: false,
+        }) {
+            log!("保存观看记录失败: {}", e);
+        }
+
+        // 结束服务器上的转码任务
+        if session.method == PlayMethod::Transcode {
+            let stop = request::stop_transcode(&host, &session.id, headers);
+            if let Err(e) = watch.bounded(stop).await {
+                log!("结束转码失败: {}", e);
+            }
+        }
+    }
+}
+
+// 比较服务器和本地的播放进度，使用较新的一个，同时返回条目总时长
+// sync 为 true 且配置了 sync_local_position 时，把较新的本地进度先回传到服务器
+async fn resume_position(
+    host: &str,
+    api_key: &str,
+    item_id: &str,
+    media_source_id: &str,
+    session: &PlaySession,
+    sync: bool,
+    headers: HeaderMap,
+) -> (Ticks, Option<Ticks>) {
+    let local = history::position(host, item_id);
+    let server = match request::get_start_position(host, api_key, item_id, headers.clone()).await {
+        Ok(server) => Some(server),
+        Err(e) => {
+            log!("获取播放进度失败，使用本地记录: {}", e);
+            None
+        }
+    };
+
+    let (server, local) = match (server, local) {
+        (Some(server), Some(local)) => (server, local),
+        (Some(server), None) => return (server.ticks, server.runtime),
+        (None, local) => return (local.map_or(Ticks::ZERO, |local| local.ticks), None),
+    };
+
+    // 上次的 Stop 没有送达时，服务器上的进度已经过时
+    if local.ticks == server.ticks || server.last_played.unwrap_or(0) >= local.updated_at {
+        return (server.ticks, server.runtime);
+    }
+
+    log!(
+        "本地进度 {} 比服务器的 {} 新，使用本地进度",
+        local.ticks,
+        server.ticks
+    );
+    if sync && Config::load().is_ok_and(|config| config.sync_local_position) {
+        let _ = playing_status(
+            local.ticks,
+            host,
+            item_id,
+            session,
+            media_source_id,
+            PlayStatus::Stop,
+            headers,
+        )
+        .await;
+    }
+
+    (local.ticks, server.runtime)
+}
+
+// 构造带通用参数的 mpv 命令，在线和离线播放共用
+pub fn mpv_command(options: Options) -> Result<Command> {
+    // 开启ipc-server
+    let ipc_server = format!("--input-ipc-server={}", ipc_server());
+
+    // 空闲启动，链接和 token 通过 IPC 传入，不出现在命令行中
+    let idle_arg = "--idle=once";
+
+    // 指定日志输出等级
+    let msg_level = "--msg-level=all=error";
+
+    // 强制立即打开播放器窗口
+    let force_window = "--force-window=immediate";
+    // set volume to 75%
+    let vol_arg = "--volume=85";
+
+    // 设置mpv请求的UA
+    let ua_arg = format!("--user-agent={}", get_ua()?);
+
+    // 设置proxy
+    let proxy_arg = format!("--http-proxy={}", get_proxy()?);
+
+    let mut mpv = MPVClient::build()?;
+
+    mpv.arg(idle_arg)
+        .arg(ua_arg)
+        .arg(vol_arg)
+        .arg(ipc_server)
+        .arg(force_window)
+        .arg(proxy_arg);
+    // --verbose 时保留 mpv 的默认日志
+    if !options.verbose {
+        mpv.arg(msg_level);
+    }
+    #[cfg(windows)]
+    mpv.creation_flags(134_217_728u32);
+
+    Ok(mpv)
+}
+
+// 检测进程退出状态
+pub async fn is_process_running(child: &mut Child) -> bool {
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    match child.try_wait() {
+        Ok(None) => true,
+        Ok(Some(_)) => false,
+        Err(_) => false,
+    }
+}
+
+// 用于显示的 mpv 命令行
+pub fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,197 @@
+use crate::log;
+#[cfg(any(windows, target_os = "macos"))]
+use anyhow::anyhow;
+#[cfg(not(target_os = "macos"))]
+use anyhow::Context;
+use anyhow::Result;
+use clap::Args;
+#[cfg(all(unix, not(target_os = "macos")))]
+use handler_common::desktop::{self, Paths, DESKTOP_FILE};
+#[cfg(windows)]
+use std::process::Command;
+
+#[derive(Args, Default)]
+pub struct Options {
+    /// Register for all users, usually needs admin rights
+    #[arg(long, overrides_with = "user")]
+    pub system: bool,
+
+    /// Register for the current user (default)
+    #[arg(long, overrides_with = "system")]
+    user: bool,
+
+    // 只打印将要写入的内容，来自全局的 --dry-run
+    #[arg(skip)]
+    pub dry_run: bool,
+}
+
+// 注册 mpv:// 协议，已注册时不做修改
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn register(options: &Options) -> Result<()> {
+    let paths = paths(options)?;
+    let exe_path = handler_path()?;
+
+    let entry = desktop::desktop_entry(&exe_path);
+    let mimeapps = read_or_empty(&paths.mimeapps)?;
+    let updated = desktop::set_default_handler(&mimeapps, Some(DESKTOP_FILE));
+
+    if options.dry_run {
+        log!("# {}\n{}", paths.desktop_file().display(), entry);
+        log!("# {}\n{}", paths.mimeapps.display(), updated);
+        return Ok(());
+    }
+
+    let current = read_or_empty(&paths.desktop_file())?;
+    if current == entry && desktop::default_handler(&mimeapps).as_deref() == Some(DESKTOP_FILE) {
+        log!("mpv:// 已注册，无需修改");
+        return Ok(());
+    }
+
+    if let Some(replaced) = desktop::install(&paths, &exe_path)? {
+        log!("已替换原有的 mpv:// 处理程序: {}", replaced);
+    }
+    log!("已写入 {}", paths.desktop_file().display());
+    log!("已将 {} 设为 mpv:// 的默认程序", DESKTOP_FILE);
+
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn unregister(options: &Options) -> Result<()> {
+    let paths = paths(options)?;
+
+    if options.dry_run {
+        let mimeapps = read_or_empty(&paths.mimeapps)?;
+        log!("# 删除 {}", paths.desktop_file().display());
+        if desktop::default_handler(&mimeapps).as_deref() == Some(DESKTOP_FILE) {
+            log!(
+                "# {}\n{}",
+                paths.mimeapps.display(),
+                desktop::set_default_handler(&mimeapps, None)
+            );
+        }
+        return Ok(());
+    }
+
+    match desktop::uninstall(&paths)? {
+        true => log!("已移除 mpv:// 注册"),
+        false => log!("mpv:// 未注册，无需修改"),
+    }
+
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn paths(options: &Options) -> Result<Paths> {
+    match options.system {
+        true => Ok(Paths::system()),
+        false => Paths::user().context("Failed to locate XDG directories"),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn read_or_empty(path: &std::path::Path) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(windows)]
+fn root(options: &Options) -> &'static str {
+    match options.system {
+        true => r"HKEY_CLASSES_ROOT\mpv",
+        false => r"HKEY_CURRENT_USER\Software\Classes\mpv",
+    }
+}
+
+#[cfg(windows)]
+fn reg(args: &[&str]) -> Result<String> {
+    let output = Command::new("reg").args(args).output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "reg {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(windows)]
+pub fn register(options: &Options) -> Result<()> {
+    let root = root(options);
+    let command_key = format!(r"{}\shell\open\command", root);
+    let command = format!("\"{}\" \"%1\"", handler_path()?);
+
+    if options.dry_run {
+        log!(
+            r#"Windows Registry Editor Version 5.00
+[{root}]
+"URL Protocol"=""
+@="mpv"
+[{root}\shell]
+[{root}\shell\open]
+[{root}\shell\open\command]
+@="{}""#,
+            command.replace('\\', "\\\\").replace('"', "\\\""),
+            root = root
+        );
+        return Ok(());
+    }
+
+    if reg(&["query", &command_key, "/ve"]).is_ok_and(|current| current.contains(&command)) {
+        log!("mpv:// 已注册，无需修改");
+        return Ok(());
+    }
+
+    reg(&["add", root, "/ve", "/d", "mpv", "/f"])?;
+    reg(&["add", root, "/v", "URL Protocol", "/d", "", "/f"])?;
+    reg(&["add", &command_key, "/ve", "/d", &command, "/f"]).context("Failed to register")?;
+    log!("已写入注册表 {}", root);
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn unregister(options: &Options) -> Result<()> {
+    let root = root(options);
+
+    if options.dry_run {
+        log!("Windows Registry Editor Version 5.00\n[-{}]", root);
+        return Ok(());
+    }
+
+    if reg(&["query", root]).is_err() {
+        log!("mpv:// 未注册，无需修改");
+        return Ok(());
+    }
+
+    reg(&["delete", root, "/f"])?;
+    log!("已删除注册表 {}", root);
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub fn register(_options: &Options) -> Result<()> {
+    Err(anyhow!(
+        "Registering mpv:// on macOS requires an application bundle"
+    ))
+}
+
+#[cfg(target_os = "macos")]
+pub fn unregister(_options: &Options) -> Result<()> {
+    Err(anyhow!(
+        "Registering mpv:// on macOS requires an application bundle"
+    ))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn handler_path() -> Result<String> {
+    let exe_path = std::env::current_exe().context("Failed to get handler path")?;
+    Ok(exe_path.display().to_string())
+}
@@ -0,0 +1,46 @@
+// 按配置的策略决定从哪里开始播放
+use crate::config::{ResumeMode, ResumePolicy};
+use crate::osd;
+use crate::ticks::Ticks;
+use anyhow::Result;
+use std::time::Duration;
+
+#[derive(Clone, Copy)]
+pub enum Start {
+    At(Ticks),
+    // 启动后在 mpv 中询问是否从该位置继续
+    Ask(Ticks),
+}
+
+// runtime 为条目总时长，未知时 remaining 策略总是继续播放
+pub fn decide(policy: &ResumePolicy, resume: Ticks, runtime: Option<Ticks>) -> Start {
+    if resume == Ticks::ZERO {
+        return Start::At(Ticks::ZERO);
+    }
+
+    match policy.mode {
+        ResumeMode::Always => Start::At(resume),
+        ResumeMode::Never => Start::At(Ticks::ZERO),
+        ResumeMode::Ask => Start::Ask(resume),
+        ResumeMode::Remaining => {
+            let min_remaining = Ticks::from_secs_f64(policy.min_remaining as f64);
+            match runtime {
+                Some(runtime) if runtime.0.saturating_sub(resume.0) < min_remaining.0 => {
+                    Start::At(Ticks::ZERO)
+                }
+                _ => Start::At(resume),
+            }
+        }
+    }
+}
+
+// 返回选择的开始位置，由调用者作为 loadfile 的 start 选项传给 mpv
+pub fn ask(resume: Ticks, policy: &ResumePolicy) -> Result<Ticks> {
+    let labels = [format!("Resume at {}", resume), "Start over".to_string()];
+    let timeout = Duration::from_secs(policy.ask_timeout);
+
+    Ok(match osd::menu("Resume playback?", &labels, 0, timeout)? {
+        0 => resume,
+        _ => Ticks::ZERO,
+    })
+}
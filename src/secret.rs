@@ -0,0 +1,267 @@
+use crate::config::{state_dir, Config, SecretStore};
+use crate::log;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+use std::process::Command;
+
+#[cfg(feature = "keyring")]
+const SERVICE: &str = "mpv-handler";
+
+// 按 scheme://host 读取 token
+pub fn get(host: &str) -> Result<Option<String>> {
+    match store()? {
+        #[cfg(feature = "keyring")]
+        SecretStore::Auto => match keyring_get(host) {
+            Ok(Some(token)) => Ok(Some(token)),
+            Ok(None) => file_get(host),
+            Err(e) => {
+                log!("读取系统密钥环失败，改用加密文件: {}", e);
+                file_get(host)
+            }
+        },
+        #[cfg(feature = "keyring")]
+        SecretStore::Keyring => keyring_get(host),
+        _ => file_get(host),
+    }
+}
+
+pub fn set(host: &str, token: &str) -> Result<()> {
+    match store()? {
+        #[cfg(feature = "keyring")]
+        SecretStore::Auto => keyring_set(host, token).or_else(|e| {
+            log!("写入系统密钥环失败，改用加密文件: {}", e);
+            file_set(host, Some(token))
+        }),
+        #[cfg(feature = "keyring")]
+        SecretStore::Keyring => keyring_set(host, token),
+        _ => file_set(host, Some(token)),
+    }
+}
+
+pub fn delete(host: &str) -> Result<()> {
+    #[cfg(feature = "keyring")]
+    match store()? {
+        SecretStore::Auto => {
+            if let Err(e) = keyring_delete(host) {
+                log!("删除系统密钥环中的 token 失败: {}", e);
+            }
+        }
+        SecretStore::Keyring => keyring_delete(host)?,
+        SecretStore::File => {}
+    }
+
+    file_set(host, None)
+}
+
+fn store() -> Result<SecretStore> {
+    let store = Config::load()?.secret_store;
+
+    #[cfg(not(feature = "keyring"))]
+    if store == SecretStore::Keyring {
+        return Err(anyhow!("mpv-handler was built without keyring support"));
+    }
+
+    Ok(store)
+}
+
+// keyring 在 tokio 线程上调用可能死锁，统一放到独立线程执行
+#[cfg(feature = "keyring")]
+fn on_thread<T: Send + 'static>(
+    f: impl FnOnce() -> keyring::Result<T> + Send + 'static,
+) -> Result<T> {
+    std::thread::spawn(f)
+        .join()
+        .map_err(|_| anyhow!("Keyring thread panicked"))?
+        .context("Keyring error")
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_get(host: &str) -> Result<Option<String>> {
+    let host = host.to_string();
+    on_thread(
+        move || match keyring::Entry::new(SERVICE, &host)?.get_password() {
+            Ok(token) => Ok(Some(token)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e),
+        },
+    )
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_set(host: &str, token: &str) -> Result<()> {
+    let (host, token) = (host.to_string(), token.to_string());
+    on_thread(move || keyring::Entry::new(SERVICE, &host)?.set_password(&token))
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_delete(host: &str) -> Result<()> {
+    let host = host.to_string();
+    on_thread(
+        move || match keyring::Entry::new(SERVICE, &host)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e),
+        },
+    )
+}
+
+// 没有系统密钥环（或 secret_store = "file"）时使用的备用存储
+// token 用 AES-256-GCM 加密后保存在状态目录的 secrets.json 中，文件只允许当前用户读写
+// 密钥由本机标识和当前用户派生，不和密文保存在一起，复制走的文件在其他机器或用户下无法解密
+fn secrets_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("secrets.json"))
+}
+
+fn cipher() -> Result<Aes256Gcm> {
+    let identity = format!("{}\0{}", machine_id()?, user_id());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, identity.as_bytes())
+        .expand(b"mpv-handler secrets", &mut key)
+        .map_err(|_| anyhow!("Failed to derive key"))?;
+
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+// 本机的标识，重装系统后会变化，需要重新保存 token
+fn machine_id() -> Result<String> {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let id = ["/etc/machine-id", "/var/lib/dbus/machine-id", "/etc/hostid"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty());
+
+    #[cfg(target_os = "macos")]
+    let id = Command::new("ioreg")
+        .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+        .output()
+        .ok()
+        .and_then(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find(|line| line.contains("IOPlatformUUID"))
+                .and_then(|line| line.rsplit('"').nth(1).map(str::to_string))
+        });
+
+    #[cfg(windows)]
+    let id = {
+        use std::os::windows::process::CommandExt;
+        Command::new("reg")
+            .args([
+                "query",
+                r"HKLM\SOFTWARE\Microsoft\Cryptography",
+                "/v",
+                "MachineGuid",
+            ])
+            .creation_flags(0x0800_0000)
+            .output()
+            .ok()
+            .and_then(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .find(|line| line.contains("MachineGuid"))
+                    .and_then(|line| line.split_whitespace().last().map(str::to_string))
+            })
+    };
+
+    id.ok_or_else(|| anyhow!("Failed to read the machine id for the secrets file"))
+}
+
+fn user_id() -> String {
+    #[cfg(unix)]
+    return unsafe { libc::getuid() }.to_string();
+    #[cfg(windows)]
+    return std::env::var("USERNAME").unwrap_or_default();
+}
+
+fn load_secrets() -> Result<HashMap<String, String>> {
+    let path = secrets_path()?;
+
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if fs::metadata(&path)?.permissions().mode() & 0o077 != 0 {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+
+    let data = fs::read_to_string(&path)?;
+    serde_json::from_str(&data).context("Failed to parse secrets file")
+}
+
+fn file_get(host: &str) -> Result<Option<String>> {
+    let Some(encoded) = load_secrets()?.remove(host) else {
+        return Ok(None);
+    };
+
+    let data = STANDARD.decode(encoded).context("Invalid secret")?;
+    if data.len() < 12 {
+        return Err(anyhow!("Invalid secret"));
+    }
+
+    let (nonce, ciphertext) = data.split_at(12);
+    let token = cipher()?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to decrypt secret for {}", host))?;
+
+    Ok(Some(String::from_utf8(token)?))
+}
+
+fn file_set(host: &str, token: Option<&str>) -> Result<()> {
+    let mut secrets = load_secrets()?;
+
+    match token {
+        Some(token) => {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = cipher()?
+                .encrypt(&nonce, token.as_bytes())
+                .map_err(|_| anyhow!("Failed to encrypt secret"))?;
+
+            let mut data = nonce.to_vec();
+            data.extend(ciphertext);
+            secrets.insert(host.to_string(), STANDARD.encode(data));
+        }
+        None => {
+            if secrets.remove(host).is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    write_private(
+        &secrets_path()?,
+        serde_json::to_string_pretty(&secrets)?.as_bytes(),
+    )
+}
+
+// 写入只允许当前用户读写的文件
+pub fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+// 处理退出信号，被终止时仍然回传 Stop 并清理 mpv
+use crate::config::Config;
+use crate::log;
+use crate::network::property;
+use crate::player;
+use crate::ticks::Ticks;
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Child;
+use std::time::Duration;
+
+// 收到信号后每一步最多等待的时间，避免退出时卡住
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+// 等待 mpv 退出，同时监听退出信号
+// 在请求服务器和启动 mpv 之前创建，这期间收到的信号也不会直接结束进程
+pub struct Watch {
+    signal: Pin<Box<dyn Future<Output = ()> + Send + Sync>>,
+    pub interrupted: bool,
+}
+
+impl Watch {
+    pub fn new() -> Watch {
+        Watch {
+            signal: Box::pin(signal()),
+            interrupted: false,
+        }
+    }
+
+    // mpv 退出或收到退出信号时返回 false
+    pub async fn running(&mut self, child: &mut Child) -> bool {
+        if self.interrupted {
+            return false;
+        }
+
+        tokio::select! {
+            running = player::is_process_running(child) => running,
+            _ = &mut self.signal => {
+                log!("收到退出信号，结束播放");
+                self.interrupted = true;
+                false
+            }
+        }
+    }
+
+    // 启动播放期间的请求和等待，收到退出信号时放弃并返回错误
+    pub async fn guard<T>(&mut self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        if self.interrupted {
+            return Err(anyhow!("Interrupted by signal"));
+        }
+
+        tokio::select! {
+            result = future => result,
+            _ = &mut self.signal => {
+                log!("收到退出信号，结束播放");
+                self.interrupted = true;
+                Err(anyhow!("Interrupted by signal"))
+            }
+        }
+    }
+
+    // 收到信号时 mpv 可能还在播放，重新读取最后的位置
+    pub async fn final_position(&self) -> Option<Ticks> {
+        if !self.interrupted {
+            return None;
+        }
+
+        blocking(property::get_time_pos).await.ok()
+    }
+
+    // 收到信号后限制等待时间，如回传 Stop
+    pub async fn bounded<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        match self.interrupted {
+            true => tokio::time::timeout(TIMEOUT, future)
+                .await
+                .map_err(|_| anyhow!("Timed out after {:?}", TIMEOUT))?,
+            false => future.await,
+        }
+    }
+
+    // 收到信号时按配置让 mpv 退出，并删除 IPC socket
+    pub async fn finish(&self, child: &mut Child) {
+        if !self.interrupted {
+            return;
+        }
+        if Config::load().is_ok_and(|config| config.keep_player_on_signal) {
+            return;
+        }
+
+        let _ = blocking(|| property::send_command(json!(["quit"]))).await;
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        while matches!(child.try_wait(), Ok(None)) {
+            if tokio::time::Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // 被强制结束的 mpv 不会删除自己的 socket
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(property::ipc_server());
+    }
+}
+
+// IPC 是阻塞调用，mpv 无响应时放弃等待
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::time::timeout(TIMEOUT, tokio::task::spawn_blocking(f))
+        .await
+        .map_err(|_| anyhow!("mpv IPC timed out"))??
+}
+
+// SIGINT、SIGTERM 或 SIGHUP，调用时立即开始监听，无法监听时永远等待
+#[cfg(unix)]
+pub fn signal() -> impl Future<Output = ()> + Send + Sync {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let signals = (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+        signal(SignalKind::hangup()),
+    );
+
+    async move {
+        let (Ok(mut interrupt), Ok(mut terminate), Ok(mut hangup)) = signals else {
+            return std::future::pending().await;
+        };
+
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+            _ = hangup.recv() => {}
+        }
+    }
+}
+
+// 控制台版本的 Ctrl-C，同样在调用时开始监听
+#[cfg(windows)]
+pub fn signal() -> impl Future<Output = ()> + Send + Sync {
+    let ctrl_c = tokio::signal::windows::ctrl_c();
+
+    async move {
+        match ctrl_c {
+            Ok(mut ctrl_c) => {
+                ctrl_c.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    }
+}
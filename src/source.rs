@@ -0,0 +1,63 @@
+// 在条目的多个版本中选择要播放的媒体源
+use crate::config::SourcePolicy;
+use crate::network::request::MediaSource;
+use crate::osd;
+use anyhow::Result;
+use std::cmp::Reverse;
+use std::time::Duration;
+
+const PICKER_TIMEOUT: Duration = Duration::from_secs(15);
+
+// 按策略选择版本，条件相同时保持服务器返回的顺序
+pub fn select(sources: &[MediaSource], policy: &SourcePolicy) -> usize {
+    let contains = |text: Option<&str>, pattern: &Option<String>| {
+        pattern.as_ref().is_some_and(|pattern| {
+            text.is_some_and(|text| text.to_lowercase().contains(&pattern.to_lowercase()))
+        })
+    };
+
+    sources
+        .iter()
+        .enumerate()
+        .max_by_key(|(i, source)| {
+            let video = source.video();
+            (
+                policy
+                    .max_bitrate
+                    .is_none_or(|max| source.bitrate.is_none_or(|bitrate| bitrate <= max)),
+                contains(source.name.as_deref(), &policy.name),
+                contains(video.and_then(|v| v.codec.as_deref()), &policy.codec),
+                match policy.highest_resolution {
+                    true => video.and_then(|v| v.height).unwrap_or(0),
+                    false => 0,
+                },
+                Reverse(*i),
+            )
+        })
+        .map_or(0, |(i, _)| i)
+}
+
+// 显示给用户的版本描述，如 "4K - hevc 2160p 58.2 Mbps"
+pub fn label(source: &MediaSource) -> String {
+    let mut parts = Vec::new();
+    if let Some(video) = source.video() {
+        parts.extend(video.codec.clone());
+        parts.extend(video.height.map(|height| format!("{}p", height)));
+    }
+    parts.extend(
+        source
+            .bitrate
+            .map(|bitrate| format!("{:.1} Mbps", bitrate as f64 / 1_000_000.0)),
+    );
+
+    match &source.name {
+        Some(name) if !parts.is_empty() => format!("{} - {}", name, parts.join(" ")),
+        Some(name) => name.clone(),
+        None => parts.join(" "),
+    }
+}
+
+// 在 mpv 中显示版本列表，按数字键选择，回车或超时使用默认版本
+pub fn pick(labels: &[String], default: usize) -> Result<usize> {
+    osd::menu("Choose a version", labels, default, PICKER_TIMEOUT)
+}
@@ -0,0 +1,109 @@
+// Emby 的时间单位，1 tick = 100 纳秒
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const PER_SECOND: u64 = 10_000_000;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Ticks(pub u64);
+
+impl Ticks {
+    pub const ZERO: Ticks = Ticks(0);
+
+    // 四舍五入到最近的 tick，负数和 NaN 视为 0
+    pub fn from_secs_f64(secs: f64) -> Ticks {
+        if secs.is_nan() || secs <= 0.0 {
+            return Ticks::ZERO;
+        }
+        Ticks((secs * PER_SECOND as f64).round() as u64)
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / PER_SECOND as f64
+    }
+
+    // 解析 mpv 返回的秒数，如 time-pos 的 "1234.567891"
+    pub fn from_mpv(text: &str) -> Option<Ticks> {
+        text.trim()
+            .trim_matches('"')
+            .parse::<f64>()
+            .ok()
+            .map(Ticks::from_secs_f64)
+    }
+
+    // 转为 mpv 接受的秒数，保留全部精度，如 "2700.1234567"
+    pub fn to_mpv(self) -> String {
+        let (secs, rest) = (self.0 / PER_SECOND, self.0 % PER_SECOND);
+        if rest == 0 {
+            return secs.to_string();
+        }
+
+        let fraction = format!("{:07}", rest);
+        format!("{}.{}", secs, fraction.trim_end_matches('0'))
+    }
+}
+
+// 如 1:02:03
+impl fmt::Display for Ticks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0 / PER_SECOND;
+        write!(f, "{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ticks;
+
+    #[test]
+    fn from_secs_rounds_to_nearest_tick() {
+        assert_eq!(Ticks::from_secs_f64(1.5), Ticks(15_000_000));
+        assert_eq!(Ticks::from_secs_f64(0.1 + 0.2), Ticks(3_000_000));
+        assert_eq!(Ticks::from_secs_f64(2_700.123_456_7), Ticks(27_001_234_567));
+        assert_eq!(Ticks::from_secs_f64(0.000_000_04), Ticks(0));
+        assert_eq!(Ticks::from_secs_f64(0.000_000_06), Ticks(1));
+    }
+
+    #[test]
+    fn from_secs_clamps_invalid_values() {
+        assert_eq!(Ticks::from_secs_f64(-3.0), Ticks::ZERO);
+        assert_eq!(Ticks::from_secs_f64(f64::NAN), Ticks::ZERO);
+    }
+
+    #[test]
+    fn mpv_time_pos_keeps_fractions() {
+        assert_eq!(
+            Ticks::from_mpv("1234.5678901234"),
+            Some(Ticks(12_345_678_901))
+        );
+        assert_eq!(Ticks::from_mpv("59.99999999"), Some(Ticks(600_000_000)));
+        assert_eq!(Ticks::from_mpv("\"12\""), Some(Ticks(120_000_000)));
+        assert_eq!(Ticks::from_mpv("null"), None);
+    }
+
+    #[test]
+    fn to_mpv_is_exact() {
+        assert_eq!(Ticks(27_000_000_000).to_mpv(), "2700");
+        assert_eq!(Ticks(15_000_001).to_mpv(), "1.5000001");
+        assert_eq!(Ticks(25_000_000).to_mpv(), "2.5");
+        assert_eq!(Ticks(1).to_mpv(), "0.0000001");
+    }
+
+    #[test]
+    fn mpv_round_trip_does_not_drift() {
+        let mut ticks = Ticks(25_331_234_567);
+        for _ in 0..100 {
+            ticks = Ticks::from_mpv(&ticks.to_mpv()).unwrap();
+        }
+        assert_eq!(ticks, Ticks(25_331_234_567));
+    }
+
+    #[test]
+    fn display_as_clock() {
+        assert_eq!(Ticks(37_230_000_000).to_string(), "1:02:03");
+        assert_eq!(Ticks(5_999_999).to_string(), "0:00:00");
+    }
+}
@@ -0,0 +1,332 @@
+// 检查链接是否安全，网页可以构造任意 mpv:// 链接
+use crate::cli::{Failure, Options};
+use crate::config::{state_dir, Config, UnknownServers};
+use crate::generic;
+use crate::log;
+use crate::network::extractor::{host_of, same_host};
+use crate::network::property;
+use crate::osd;
+use crate::player;
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+// Emby 推流和字幕链接只能是 http(s)
+pub const STREAM_SCHEMES: &[&str] = &["http", "https"];
+
+// mpv 内置的本地文件和特殊协议，任何配置下都不允许
+// ytdl 只有在 [generic] 中明确允许时才能使用
+const DANGEROUS_SCHEMES: &[&str] = &[
+    "file",
+    "av",
+    "avdevice",
+    "lavf",
+    "ffmpeg",
+    "edl",
+    "memory",
+    "hex",
+    "fd",
+    "fdclose",
+    "mf",
+    "slice",
+    "appending",
+    "null",
+    "cdda",
+    "dvd",
+    "dvdnav",
+    "dvb",
+    "bd",
+    "bluray",
+    "br",
+    "tv",
+    "pvr",
+    "v4l2",
+    "smb",
+];
+
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+// 检查链接的协议，拒绝本地文件、mpv 的特殊协议和像命令行选项的字符串
+pub fn check_url(url: &str, schemes: &[impl AsRef<str>]) -> Result<()> {
+    if url.trim_start().starts_with('-') {
+        return Err(anyhow!("Option-like url rejected: {}", url));
+    }
+    if url.chars().any(char::is_control) {
+        return Err(anyhow!("Url with control characters rejected"));
+    }
+
+    // 没有协议的链接会被 mpv 当作本地路径
+    let scheme = match url.split_once(':') {
+        Some((scheme, _))
+            if !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) =>
+        {
+            scheme.to_ascii_lowercase()
+        }
+        _ => return Err(anyhow!("Url without a scheme rejected: {}", url)),
+    };
+
+    if DANGEROUS_SCHEMES.contains(&scheme.as_str()) {
+        return Err(anyhow!("Scheme {} is not allowed", scheme));
+    }
+    if !schemes
+        .iter()
+        .any(|allowed| allowed.as_ref().eq_ignore_ascii_case(&scheme))
+    {
+        return Err(anyhow!("Scheme {} is not allowed", scheme));
+    }
+
+    Ok(())
+}
+
+// 检查视频和字幕链接，普通链接按 [generic] 的规则
+pub fn check_links(video_url: &str, subfile_url: &str, generic: bool) -> Result<()> {
+    check_links_with(
+        &Config::load()?,
+        &load_trusted(),
+        video_url,
+        subfile_url,
+        generic,
+    )
+}
+
+// 推流的字幕不在同一服务器时，字幕的服务器也必须是可信的
+fn check_links_with(
+    config: &Config,
+    saved: &[String],
+    video_url: &str,
+    subfile_url: &str,
+    generic: bool,
+) -> Result<()> {
+    let mut links = [video_url, subfile_url]
+        .into_iter()
+        .filter(|url| !url.is_empty());
+    if generic {
+        let policy = generic::policy(config)?;
+        return links.try_for_each(|url| generic::allowed(policy, url));
+    }
+    for url in links {
+        check_url(url, STREAM_SCHEMES)?;
+    }
+
+    let host = host_of(&Url::parse(video_url).context("Invalid streaming url")?)?;
+    if subfile_url.is_empty() || same_host(subfile_url, &host) {
+        return Ok(());
+    }
+    let subfile_host = host_of(&Url::parse(subfile_url).context("Invalid subtitle url")?)?;
+    match is_trusted(config, saved, &subfile_host) {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "Subtitle server {} is not trusted, add it to trusted_servers",
+            subfile_host
+        )),
+    }
+}
+
+// 检查推流链接的服务器是否可信，未知的服务器按配置确认或拒绝
+// 需要在向该服务器发出任何请求之前调用
+pub async fn check_server(host: &str, options: Options) -> Result<()> {
+    let config = Config::load()?;
+    if is_trusted(&config, &load_trusted(), host) {
+        return Ok(());
+    }
+
+    match config.unknown_servers {
+        UnknownServers::Allow => Ok(()),
+        UnknownServers::Deny => Err(anyhow!(
+            "Server {} is not trusted, add it to trusted_servers",
+            host
+        ))
+        .context(Failure::Parse),
+        UnknownServers::Ask if options.dry_run => {
+            log!("首次使用 {}，将在 mpv 中确认", host);
+            Ok(())
+        }
+        // 等待选择时会阻塞，不占用异步运行时的线程
+        UnknownServers::Ask => {
+            let host = host.to_string();
+            tokio::task::spawn_blocking(move || confirm(&host, options))
+                .await?
+                .context(Failure::Parse)
+        }
+    }
+}
+
+// saved 为在 mpv 中选择始终信任的服务器
+fn is_trusted(config: &Config, saved: &[String], host: &str) -> bool {
+    let origin = |url: &String| {
+        Url::parse(url)
+            .ok()
+            .and_then(|url| host_of(&url).ok())
+            .is_some_and(|origin| origin == host)
+    };
+
+    config.find_server(host).is_some()
+        || config.trusted_servers.iter().any(origin)
+        || saved.iter().any(origin)
+}
+
+// 在 mpv 中询问是否信任该服务器，没有正在运行的 mpv 时临时启动一个
+fn confirm(host: &str, options: Options) -> Result<()> {
+    let mut temporary = match property::send_command(json!(["get_property", "mpv-version"])) {
+        Ok(_) => None,
+        Err(_) => {
+            let mut child = player::mpv_command(options)?
+                .spawn()
+                .context("Failed to start mpv")?;
+            if let Err(e) = property::wait_ready(Duration::from_secs(10)) {
+                let _ = child.kill();
+                return Err(e);
+            }
+            Some(child)
+        }
+    };
+
+    let labels = [
+        "Cancel".to_string(),
+        "Allow once".to_string(),
+        "Always allow".to_string(),
+    ];
+    let choice = osd::menu(
+        &format!("Open a link from the untrusted server {}?", host),
+        &labels,
+        0,
+        CONFIRM_TIMEOUT,
+    );
+
+    // 临时的 mpv 退出后再启动播放用的 mpv
+    if let Some(child) = &mut temporary {
+        let _ = property::send_command(json!(["quit"]));
+        let _ = child.wait();
+    }
+
+    match choice? {
+        1 => Ok(()),
+        2 => {
+            save_trusted(host)?;
+            log!("已信任 {}", host);
+            Ok(())
+        }
+        _ => Err(anyhow!("Server {} was not trusted", host)),
+    }
+}
+
+// 在 mpv 中选择始终信任的服务器，和配置中的 trusted_servers 分开保存
+fn load_trusted() -> Vec<String> {
+    trusted_path()
+        .and_then(|path| Ok(std::fs::read_to_string(path)?))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn save_trusted(host: &str) -> Result<()> {
+    let mut trusted = load_trusted();
+    trusted.push(host.to_string());
+    std::fs::write(trusted_path()?, serde_json::to_string_pretty(&trusted)?)
+        .context("Failed to save trusted servers")
+}
+
+fn trusted_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("trusted_servers.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_links_with, check_url, STREAM_SCHEMES};
+    use crate::config::Config;
+
+    fn config(text: &str) -> Config {
+        toml::from_str(&format!("mpv = \"mpv\"\n{}", text)).unwrap()
+    }
+
+    #[test]
+    fn check_url_accepts_allowed_schemes() {
+        assert!(check_url("https://emby.example.com/videos/1/stream", STREAM_SCHEMES).is_ok());
+        assert!(check_url("HTTP://emby.example.com/", STREAM_SCHEMES).is_ok());
+        assert!(check_url("magnet:?xt=urn:btih:abc", &["magnet"]).is_ok());
+    }
+
+    #[test]
+    fn check_url_rejects_unsafe_urls() {
+        for url in [
+            "file:///etc/passwd",
+            "edl://%0%file:///etc/passwd",
+            "ytdl://example.com",
+            "--script=/tmp/evil.lua",
+            " -v",
+            "/home/user/video.mkv",
+            "C:\\Users\\video.mkv",
+            "https://example.com/\nloadfile",
+            "",
+        ] {
+            assert!(check_url(url, STREAM_SCHEMES).is_err(), "{}", url);
+        }
+
+        // 配置中允许也不能使用 mpv 的特殊协议和设备
+        for scheme in [
+            "memory", "avdevice", "bluray", "br", "dvd", "dvdnav", "tv", "pvr", "v4l2",
+        ] {
+            let url = format!("{}://0", scheme);
+            assert!(check_url(&url, &[scheme]).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn check_links_allows_subtitle_on_the_same_server() {
+        let config = config("");
+        let video = "https://emby.example.com/emby/videos/1/stream.mkv";
+
+        assert!(check_links_with(&config, &[], video, "", false).is_ok());
+        assert!(check_links_with(
+            &config,
+            &[],
+            video,
+            "https://emby.example.com/emby/videos/1/subtitles/2/stream.srt",
+            false
+        )
+        .is_ok());
+        assert!(check_links_with(&config, &[], video, "file:///tmp/a.srt", false).is_err());
+    }
+
+    #[test]
+    fn check_links_requires_trusted_subtitle_server() {
+        let video = "https://emby.example.com/emby/videos/1/stream.mkv";
+        let subtitle = "https://subs.example.org/a.srt";
+
+        assert!(check_links_with(&config(""), &[], video, subtitle, false).is_err());
+        // 端口不同也是不同的服务器
+        assert!(check_links_with(
+            &config(""),
+            &[],
+            video,
+            "https://emby.example.com:8443/a.srt",
+            false
+        )
+        .is_err());
+
+        let trusted = config("trusted_servers = [\"https://subs.example.org\"]");
+        assert!(check_links_with(&trusted, &[], video, subtitle, false).is_ok());
+
+        let saved = ["https://subs.example.org".to_string()];
+        assert!(check_links_with(&config(""), &saved, video, subtitle, false).is_ok());
+
+        let server = config("[[servers]]\nname = \"subs\"\nurl = \"https://subs.example.org/\"");
+        assert!(check_links_with(&server, &[], video, subtitle, false).is_ok());
+    }
+
+    #[test]
+    fn check_links_uses_generic_policy() {
+        let video = "https://video.example.com/a.mp4";
+
+        assert!(check_links_with(&config(""), &[], video, "", true).is_err());
+
+        let config = config("[generic]\nhosts = [\"example.com\"]");
+        assert!(check_links_with(&config, &[], video, "", true).is_ok());
+        assert!(check_links_with(&config, &[], video, "https://other.org/a.srt", true).is_err());
+    }
+}